@@ -0,0 +1,366 @@
+//! Limiting the bandwidth of [`tokio`](https://docs.rs/tokio) streams, one unit per byte.
+
+use std::{
+    fmt,
+    future::Future,
+    io, mem,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{ready, Context, Poll},
+    time::Duration,
+};
+
+use pin_project_lite::pin_project;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::{lock, Clock, Gcr, GcrObserver, GcrRequestError, SystemClock};
+
+/// The limiter shared by throttled streams, along with the wait after the last poll that found no
+/// bytes available and the bytes granted to a transfer that has not finished yet
+struct AsyncThrottle<Z, T, C: Clock, O: GcrObserver> {
+    /// The limiter that every byte is requested from
+    limiter: Arc<Mutex<Gcr<C, O>>>,
+    /// Returns a future that completes after the given duration
+    sleep: Z,
+    /// The wait for the next byte, if it has not finished yet
+    sleeping: Option<Pin<Box<T>>>,
+    /// The bytes consumed for a transfer the inner stream has not finished yet
+    granted: usize,
+}
+
+impl<Z, T, C: Clock, O: GcrObserver> AsyncThrottle<Z, T, C, O> {
+    /// Create a new [`AsyncThrottle`] that requests bytes from `limiter`
+    fn new(limiter: Arc<Mutex<Gcr<C, O>>>, sleep: Z) -> Self {
+        Self {
+            limiter,
+            sleep,
+            sleeping: None,
+            granted: 0,
+        }
+    }
+
+    /// Keep `used` of the granted bytes once a transfer has finished, and return the rest
+    fn settle(&mut self, used: usize) {
+        let unused = mem::take(&mut self.granted).saturating_sub(used);
+        if unused > 0 {
+            lock(&self.limiter).release(unused as u64);
+        }
+    }
+}
+
+impl<Z, T, C, O> AsyncThrottle<Z, T, C, O>
+where
+    Z: Fn(Duration) -> T,
+    T: Future<Output = ()>,
+    C: Clock,
+    O: GcrObserver,
+{
+    /// Get how many of `len` bytes to transfer, consuming as many as are available and waiting for
+    /// at least one.
+    ///
+    /// Never more than the capacity is consumed, so transfers are shortened instead of exceeding
+    /// it. If nothing is available, a timer is started for when the next byte is. Bytes that were
+    /// already granted to a transfer the inner stream left pending are reused instead.
+    fn poll_acquire(&mut self, cx: &mut Context<'_>, len: usize) -> Poll<io::Result<usize>> {
+        if self.granted > 0 {
+            return Poll::Ready(Ok(self.granted.min(len)));
+        }
+
+        loop {
+            if let Some(sleeping) = &mut self.sleeping {
+                ready!(sleeping.as_mut().poll(cx));
+                self.sleeping = None;
+            }
+
+            // Another stream may have taken the bytes while we were waiting, so try again
+            let wait = {
+                let mut gcr = lock(&self.limiter);
+                let n = gcr.request_up_to(len as u64);
+                if n > 0 {
+                    self.granted = n as usize;
+                    return Poll::Ready(Ok(self.granted));
+                }
+
+                match gcr.check(1) {
+                    Ok(()) => Duration::ZERO,
+                    Err(GcrRequestError::DeniedFor(duration)) => duration,
+                    Err(error) => return Poll::Ready(Err(io::Error::other(error))),
+                }
+            };
+            self.sleeping = Some(Box::pin((self.sleep)(wait)));
+        }
+    }
+}
+
+/// Returns the bytes granted to a transfer that never finished
+impl<Z, T, C: Clock, O: GcrObserver> Drop for AsyncThrottle<Z, T, C, O> {
+    fn drop(&mut self) {
+        self.settle(0);
+    }
+}
+
+impl<Z, T, C: Clock + fmt::Debug, O: GcrObserver + fmt::Debug> fmt::Debug
+    for AsyncThrottle<Z, T, C, O>
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AsyncThrottle")
+            .field("limiter", &self.limiter)
+            .field("sleeping", &self.sleeping.is_some())
+            .field("granted", &self.granted)
+            .finish_non_exhaustive()
+    }
+}
+
+pin_project! {
+    /// An [`AsyncRead`] wrapper that limits how many bytes per second are read, by requesting one
+    /// unit from a [`Gcr`] for every byte.
+    ///
+    /// Each poll reads at most as many bytes as are available, so reads are shortened rather than
+    /// exceeding the capacity. If none are, the read waits by awaiting `sleep` until the next byte
+    /// is, which lets any async runtime (or a test clock) be used. While the inner stream is not
+    /// ready, the bytes granted to the read are held for the next poll instead of being requested
+    /// again. Several streams can share one limit with [`ThrottledAsyncRead::shared`].
+    ///
+    /// ```rust,ignore
+    /// let rate = Gcr::new(10 * 1024 * 1024, Duration::from_secs(1), None)?; // 10 MiB/s
+    /// let mut reader = ThrottledAsyncRead::new(socket, rate, tokio::time::sleep);
+    /// reader.read_to_end(&mut buf).await?;
+    /// ```
+    pub struct ThrottledAsyncRead<R, Z, T, C: Clock = SystemClock, O: GcrObserver = ()> {
+        // The stream being read from
+        #[pin]
+        inner: R,
+        // Where the bytes are requested from
+        throttle: AsyncThrottle<Z, T, C, O>,
+    }
+}
+
+impl<R, Z, T, C: Clock, O: GcrObserver> ThrottledAsyncRead<R, Z, T, C, O> {
+    /// Create a new [`ThrottledAsyncRead`] that limits reads from `inner` with `gcr` and waits
+    /// for bytes to become available with `sleep`
+    pub fn new(inner: R, gcr: Gcr<C, O>, sleep: Z) -> Self
+    where
+        Z: Fn(Duration) -> T,
+        T: Future<Output = ()>,
+    {
+        Self::shared(inner, Arc::new(Mutex::new(gcr)), sleep)
+    }
+
+    /// Create a new [`ThrottledAsyncRead`] that limits reads from `inner` with a `limiter` that
+    /// may also be used by other streams, such as the [limiter](ThrottledAsyncRead::limiter) of
+    /// another [`ThrottledAsyncRead`] or [`ThrottledAsyncWrite`]
+    pub fn shared(inner: R, limiter: Arc<Mutex<Gcr<C, O>>>, sleep: Z) -> Self
+    where
+        Z: Fn(Duration) -> T,
+        T: Future<Output = ()>,
+    {
+        Self {
+            inner,
+            throttle: AsyncThrottle::new(limiter, sleep),
+        }
+    }
+
+    /// Get the shared [`Gcr`], such as to share it with another stream or to
+    /// [adjust](Gcr::adjust) it while reading
+    pub fn limiter(&self) -> &Arc<Mutex<Gcr<C, O>>> {
+        &self.throttle.limiter
+    }
+
+    /// Get a reference to the stream being read from
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    /// Get a mutable reference to the stream being read from. Reading from it directly bypasses
+    /// the limit
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.inner
+    }
+
+    /// Get a pinned mutable reference to the stream being read from. Reading from it directly
+    /// bypasses the limit
+    pub fn get_pin_mut(self: Pin<&mut Self>) -> Pin<&mut R> {
+        self.project().inner
+    }
+
+    /// Unwrap the stream being read from
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R, Z, T, C, O> AsyncRead for ThrottledAsyncRead<R, Z, T, C, O>
+where
+    R: AsyncRead,
+    Z: Fn(Duration) -> T,
+    T: Future<Output = ()>,
+    C: Clock,
+    O: GcrObserver,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.project();
+        if buf.remaining() == 0 {
+            return this.inner.poll_read(cx, buf);
+        }
+
+        // Only let the inner stream fill as many bytes as were granted
+        let n = ready!(this.throttle.poll_acquire(cx, buf.remaining()))?;
+        let mut limited = ReadBuf::new(buf.initialize_unfilled_to(n));
+        let result = this.inner.poll_read(cx, &mut limited);
+        match result {
+            Poll::Ready(Ok(())) => {
+                let read = limited.filled().len();
+                buf.advance(read);
+                this.throttle.settle(read);
+            }
+            Poll::Ready(Err(_)) => this.throttle.settle(0),
+            // Keep the granted bytes for the next poll instead of requesting them again
+            Poll::Pending => {}
+        }
+        result
+    }
+}
+
+impl<R: fmt::Debug, Z, T, C: Clock + fmt::Debug, O: GcrObserver + fmt::Debug> fmt::Debug
+    for ThrottledAsyncRead<R, Z, T, C, O>
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ThrottledAsyncRead")
+            .field("inner", &self.inner)
+            .field("throttle", &self.throttle)
+            .finish()
+    }
+}
+
+pin_project! {
+    /// An [`AsyncWrite`] wrapper that limits how many bytes per second are written, by requesting
+    /// one unit from a [`Gcr`] for every byte.
+    ///
+    /// Each poll writes at most as many bytes as are available, so writes are shortened rather
+    /// than exceeding the capacity. If none are, the write waits by awaiting `sleep` until the
+    /// next byte is, which lets any async runtime (or a test clock) be used. While the inner
+    /// stream is not ready, the bytes granted to the write are held for the next poll instead of
+    /// being requested again. Several streams can share one limit with
+    /// [`ThrottledAsyncWrite::shared`].
+    ///
+    /// ```rust,ignore
+    /// let rate = Gcr::new(10 * 1024 * 1024, Duration::from_secs(1), None)?; // 10 MiB/s
+    /// let mut writer = ThrottledAsyncWrite::new(socket, rate, tokio::time::sleep);
+    /// writer.write_all(&buf).await?;
+    /// ```
+    pub struct ThrottledAsyncWrite<W, Z, T, C: Clock = SystemClock, O: GcrObserver = ()> {
+        // The stream being written to
+        #[pin]
+        inner: W,
+        // Where the bytes are requested from
+        throttle: AsyncThrottle<Z, T, C, O>,
+    }
+}
+
+impl<W, Z, T, C: Clock, O: GcrObserver> ThrottledAsyncWrite<W, Z, T, C, O> {
+    /// Create a new [`ThrottledAsyncWrite`] that limits writes to `inner` with `gcr` and waits
+    /// for bytes to become available with `sleep`
+    pub fn new(inner: W, gcr: Gcr<C, O>, sleep: Z) -> Self
+    where
+        Z: Fn(Duration) -> T,
+        T: Future<Output = ()>,
+    {
+        Self::shared(inner, Arc::new(Mutex::new(gcr)), sleep)
+    }
+
+    /// Create a new [`ThrottledAsyncWrite`] that limits writes to `inner` with a `limiter` that
+    /// may also be used by other streams, such as the [limiter](ThrottledAsyncWrite::limiter) of
+    /// another [`ThrottledAsyncWrite`] or [`ThrottledAsyncRead`]
+    pub fn shared(inner: W, limiter: Arc<Mutex<Gcr<C, O>>>, sleep: Z) -> Self
+    where
+        Z: Fn(Duration) -> T,
+        T: Future<Output = ()>,
+    {
+        Self {
+            inner,
+            throttle: AsyncThrottle::new(limiter, sleep),
+        }
+    }
+
+    /// Get the shared [`Gcr`], such as to share it with another stream or to
+    /// [adjust](Gcr::adjust) it while writing
+    pub fn limiter(&self) -> &Arc<Mutex<Gcr<C, O>>> {
+        &self.throttle.limiter
+    }
+
+    /// Get a reference to the stream being written to
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    /// Get a mutable reference to the stream being written to. Writing to it directly bypasses
+    /// the limit
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+
+    /// Get a pinned mutable reference to the stream being written to. Writing to it directly
+    /// bypasses the limit
+    pub fn get_pin_mut(self: Pin<&mut Self>) -> Pin<&mut W> {
+        self.project().inner
+    }
+
+    /// Unwrap the stream being written to
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+impl<W, Z, T, C, O> AsyncWrite for ThrottledAsyncWrite<W, Z, T, C, O>
+where
+    W: AsyncWrite,
+    Z: Fn(Duration) -> T,
+    T: Future<Output = ()>,
+    C: Clock,
+    O: GcrObserver,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.project();
+        if buf.is_empty() {
+            return this.inner.poll_write(cx, buf);
+        }
+
+        // Only pass the inner stream as many bytes as were granted
+        let n = ready!(this.throttle.poll_acquire(cx, buf.len()))?;
+        let result = this.inner.poll_write(cx, &buf[..n]);
+        match result {
+            Poll::Ready(Ok(written)) => this.throttle.settle(written.min(n)),
+            Poll::Ready(Err(_)) => this.throttle.settle(0),
+            // Keep the granted bytes for the next poll instead of requesting them again
+            Poll::Pending => {}
+        }
+        result
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().inner.poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().inner.poll_shutdown(cx)
+    }
+}
+
+impl<W: fmt::Debug, Z, T, C: Clock + fmt::Debug, O: GcrObserver + fmt::Debug> fmt::Debug
+    for ThrottledAsyncWrite<W, Z, T, C, O>
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ThrottledAsyncWrite")
+            .field("inner", &self.inner)
+            .field("throttle", &self.throttle)
+            .finish()
+    }
+}
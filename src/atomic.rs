@@ -0,0 +1,148 @@
+//! A thread-safe, lock-free variant of [`Gcr`](crate::Gcr).
+
+use core::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+#[cfg(feature = "std")]
+use crate::SystemClock;
+use crate::{Clock, DefaultClock, GcrCreationError, GcrRequestError, Params, Timestamp};
+
+/// A generic cell rate (GCR) algorithm instance that can be shared between threads.
+///
+/// This has the same semantics as [`Gcr`](crate::Gcr), but stores the theoretical arrival time
+/// as an atomic [`Timestamp`] and updates it with a compare-and-swap loop, so requests only
+/// need `&self`. Only available on targets with 64-bit atomics.
+///
+/// Since the theoretical arrival time has to fit in 64 bits, it is rounded up to the next whole
+/// nanosecond after each request. This can only ever deny units, never allow extra ones.
+#[derive(Debug)]
+pub struct AtomicGcr<C: Clock = DefaultClock> {
+    /// The rate, period, and max burst
+    params: Params,
+    /// The theoretical arrival time of the next unit, in nanoseconds
+    theoretical_arrival_time: AtomicU64,
+    /// The source of the current time
+    clock: C,
+}
+
+#[cfg(feature = "std")]
+impl AtomicGcr {
+    /// Create a new [`AtomicGcr`] instance that uses the [`SystemClock`].
+    ///
+    /// Accepts the same parameters as [`Gcr::new`](crate::Gcr::new).
+    ///
+    /// # Errors
+    /// - [`GcrCreationError::ZeroRate`] - if the rate was zero
+    /// - [`GcrCreationError::ZeroEmissionInterval`] - if the period was zero
+    /// - [`GcrCreationError::DelayToleranceOverflow`] - if the parameters are out of range
+    pub fn new(
+        rate: u64,
+        period: Duration,
+        max_burst: Option<u64>,
+    ) -> Result<Self, GcrCreationError> {
+        Self::with_clock(rate, period, max_burst, SystemClock)
+    }
+}
+
+impl<C: Clock> AtomicGcr<C> {
+    /// Create a new [`AtomicGcr`] instance that reads the current time from `clock`.
+    ///
+    /// Accepts the same parameters as [`Gcr::new`](crate::Gcr::new).
+    ///
+    /// # Errors
+    /// - [`GcrCreationError::ZeroRate`] - if the rate was zero
+    /// - [`GcrCreationError::ZeroEmissionInterval`] - if the period was zero
+    /// - [`GcrCreationError::DelayToleranceOverflow`] - if the parameters are out of range
+    pub fn with_clock(
+        rate: u64,
+        period: Duration,
+        max_burst: Option<u64>,
+        clock: C,
+    ) -> Result<Self, GcrCreationError> {
+        let params = Params::new(rate, period, max_burst)?;
+
+        // This is set to the current time so we can instantly have our full burst
+        let theoretical_arrival_time = AtomicU64::new(clock.now().as_nanos());
+
+        Ok(Self {
+            params,
+            theoretical_arrival_time,
+            clock,
+        })
+    }
+
+    /// Get a reference to the [`Clock`] used by this instance
+    pub fn clock(&self) -> &C {
+        &self.clock
+    }
+
+    /// Load the theoretical arrival time of the next unit, in nanoseconds
+    fn theoretical_arrival_time(&self) -> u64 {
+        self.theoretical_arrival_time.load(Ordering::Acquire)
+    }
+
+    /// Convert a theoretical arrival time in nanoseconds to ticks
+    fn ticks(&self, theoretical_arrival_time: u64) -> u128 {
+        self.params
+            .ticks(Timestamp::from_nanos(theoretical_arrival_time))
+    }
+
+    /// Get the capacity of the rate limiter at a given time.
+    ///
+    /// Note: this function calculates the capacity on the fly
+    pub fn capacity_at(&self, now: impl Into<Timestamp>) -> u64 {
+        self.params
+            .capacity(self.ticks(self.theoretical_arrival_time()), now.into())
+    }
+
+    /// Get the current capacity of the rate limiter
+    ///
+    /// Note: this function calculates the capacity on the fly
+    pub fn capacity(&self) -> u64 {
+        self.capacity_at(self.clock.now())
+    }
+
+    /// Request `n` units from the rate limiter.
+    ///
+    /// If the request was allowed through, this will return `Ok(())`. If not, it will return an error with the reason.
+    ///
+    /// # Errors
+    /// - See [`Gcr::request`](crate::Gcr::request)
+    pub fn request(&self, n: u64) -> Result<(), GcrRequestError> {
+        self.request_at(n, self.clock.now())
+    }
+
+    /// Request `n` units from the rate limiter as if the request was made at `now`.
+    ///
+    /// # Errors
+    /// - See [`Gcr::request`](crate::Gcr::request)
+    pub fn request_at(&self, n: u64, now: impl Into<Timestamp>) -> Result<(), GcrRequestError> {
+        let now = now.into();
+
+        let mut theoretical_arrival_time = self.theoretical_arrival_time();
+        loop {
+            // Deny the request if it exceeds capacity, otherwise account for the new units consumed
+            let new_theoretical_arrival_time =
+                self.params
+                    .request(self.ticks(theoretical_arrival_time), now, n)?;
+            let new_theoretical_arrival_time = self
+                .params
+                .timestamp(new_theoretical_arrival_time)
+                .map_err(|field| GcrRequestError::InstantOverflow { field })?;
+
+            // Only commit if no other thread has updated the theoretical arrival time in
+            // the meantime. Otherwise, retry against the new value
+            match self.theoretical_arrival_time.compare_exchange_weak(
+                theoretical_arrival_time,
+                new_theoretical_arrival_time.as_nanos(),
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => return Ok(()),
+                Err(actual) => theoretical_arrival_time = actual,
+            }
+        }
+    }
+}
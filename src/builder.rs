@@ -0,0 +1,238 @@
+//! A builder for [`Gcr`] instances with more configuration than [`Gcr::new`].
+
+use core::time::Duration;
+
+#[cfg(feature = "std")]
+use crate::SystemClock;
+use crate::{Clock, DefaultClock, Gcr, GcrCreationError, InstantField, Params};
+
+/// A builder for [`Gcr`] instances, created with [`Gcr::builder`].
+///
+/// The rate is set with either [`GcrBuilder::rate`] and [`GcrBuilder::per`], or
+/// [`GcrBuilder::emission_interval`]. Everything else is optional.
+///
+#[cfg_attr(feature = "std", doc = "```rust")]
+#[cfg_attr(not(feature = "std"), doc = "```rust,ignore")]
+/// use gcr::Gcr;
+/// use std::time::Duration;
+///
+/// let mut rate = Gcr::builder()
+///     .rate(10)
+///     .per(Duration::from_secs(1))
+///     .burst_duration(Duration::from_secs(3))
+///     .initial_capacity(0)
+///     .build()
+///     .unwrap();
+///
+/// rate.request(1).unwrap_err(); // Starts empty instead of full
+/// ```
+#[derive(Clone, Debug)]
+pub struct GcrBuilder<C: Clock = DefaultClock> {
+    /// The number of units to "refill" per `period`
+    rate: Option<u64>,
+    /// The amount of time between each "refill"
+    period: Option<Duration>,
+    /// The amount of time it takes to "refill" a single unit
+    emission_interval: Option<Duration>,
+    /// The maximum number of units to allow in a single request
+    burst: Option<u64>,
+    /// The amount of time it takes to "refill" the maximum burst
+    burst_duration: Option<Duration>,
+    /// The number of units available when the instance is created
+    initial_capacity: Option<u64>,
+    /// The longest a reservation is allowed to wait for its units
+    max_wait: Option<Duration>,
+    /// The most units [`Gcr::charge`] may consume beyond the capacity
+    max_debt: Option<u64>,
+    /// The source of the current time
+    clock: C,
+}
+
+#[cfg(feature = "std")]
+impl Default for GcrBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(feature = "std")]
+impl GcrBuilder {
+    /// Create a new [`GcrBuilder`] that uses the [`SystemClock`]
+    pub fn new() -> Self {
+        Self::with_clock(SystemClock)
+    }
+}
+
+#[cfg(feature = "std")]
+impl Gcr {
+    /// Create a new [`GcrBuilder`]
+    pub fn builder() -> GcrBuilder {
+        GcrBuilder::new()
+    }
+}
+
+impl<C: Clock> GcrBuilder<C> {
+    /// Create a new [`GcrBuilder`] that reads the current time from `clock`
+    pub fn with_clock(clock: C) -> Self {
+        Self {
+            rate: None,
+            period: None,
+            emission_interval: None,
+            burst: None,
+            burst_duration: None,
+            initial_capacity: None,
+            max_wait: None,
+            max_debt: None,
+            clock,
+        }
+    }
+
+    /// Set the number of units to "refill" per [`GcrBuilder::per`]
+    pub fn rate(mut self, rate: u64) -> Self {
+        self.rate = Some(rate);
+        self
+    }
+
+    /// Set the amount of time over which [`GcrBuilder::rate`] units are "refilled"
+    pub fn per(mut self, period: Duration) -> Self {
+        self.period = Some(period);
+        self
+    }
+
+    /// Set the amount of time it takes to "refill" a single unit, instead of using
+    /// [`GcrBuilder::rate`] and [`GcrBuilder::per`]
+    pub fn emission_interval(mut self, emission_interval: Duration) -> Self {
+        self.emission_interval = Some(emission_interval);
+        self
+    }
+
+    /// Set the maximum number of units to allow in a single request.
+    ///
+    /// If neither this nor [`GcrBuilder::burst_duration`] is set, this will be set to the rate.
+    pub fn burst(mut self, burst: u64) -> Self {
+        self.burst = Some(burst);
+        self
+    }
+
+    /// Set the maximum burst to the number of units that are "refilled" over `burst_duration`,
+    /// instead of using [`GcrBuilder::burst`]
+    pub fn burst_duration(mut self, burst_duration: Duration) -> Self {
+        self.burst_duration = Some(burst_duration);
+        self
+    }
+
+    /// Set the number of units available when the instance is created.
+    ///
+    /// Defaults to the maximum burst, so the instance starts full.
+    pub fn initial_capacity(mut self, initial_capacity: u64) -> Self {
+        self.initial_capacity = Some(initial_capacity);
+        self
+    }
+
+    /// Set the longest a reservation is allowed to wait for its units.
+    /// See [`Gcr::with_max_wait`].
+    pub fn max_wait(mut self, max_wait: Duration) -> Self {
+        self.max_wait = Some(max_wait);
+        self
+    }
+
+    /// Set the most units [`Gcr::charge`] may consume beyond the capacity.
+    /// See [`Gcr::with_max_debt`].
+    pub fn max_debt(mut self, max_debt: u64) -> Self {
+        self.max_debt = Some(max_debt);
+        self
+    }
+
+    /// Set the [`Clock`] the instance reads the current time from
+    pub fn clock<C2: Clock>(self, clock: C2) -> GcrBuilder<C2> {
+        GcrBuilder {
+            rate: self.rate,
+            period: self.period,
+            emission_interval: self.emission_interval,
+            burst: self.burst,
+            burst_duration: self.burst_duration,
+            initial_capacity: self.initial_capacity,
+            max_wait: self.max_wait,
+            max_debt: self.max_debt,
+            clock,
+        }
+    }
+
+    /// Build the [`Gcr`] instance.
+    ///
+    /// # Errors
+    /// - [`GcrCreationError::MissingRate`] - if neither a rate nor an emission interval was set
+    /// - [`GcrCreationError::MissingPeriod`] - if a rate was set without a period
+    /// - [`GcrCreationError::ConflictingRate`] - if both a rate and an emission interval were set
+    /// - [`GcrCreationError::ZeroRate`] - if the rate was zero
+    /// - [`GcrCreationError::ZeroEmissionInterval`] - if the emission interval or period was zero
+    /// - [`GcrCreationError::MissingBurst`] - if an emission interval was set without a burst
+    /// - [`GcrCreationError::ConflictingBurst`] - if both a burst and a burst duration were set
+    /// - [`GcrCreationError::BurstDurationTooShort`] - if the burst duration was shorter than the emission interval
+    /// - [`GcrCreationError::InitialCapacityTooLarge`] - if the initial capacity was larger than the maximum burst
+    /// - [`GcrCreationError::BurstDurationTooLong`] - if the burst duration allowed more than `u64::MAX` units
+    /// - [`GcrCreationError::DelayToleranceOverflow`] or [`GcrCreationError::InstantOverflow`] - if the parameters are out of range
+    pub fn build(self) -> Result<Gcr<C>, GcrCreationError> {
+        // An emission interval is the same as a period for a single unit
+        let (rate, period) = match (self.rate, self.period, self.emission_interval) {
+            (None, None, Some(emission_interval)) => (1, emission_interval),
+            (_, _, Some(_)) => return Err(GcrCreationError::ConflictingRate),
+            (Some(0), Some(_), None) => return Err(GcrCreationError::ZeroRate),
+            (Some(rate), Some(period), None) => (rate, period),
+            (Some(_), None, None) => return Err(GcrCreationError::MissingPeriod),
+            (None, _, None) => return Err(GcrCreationError::MissingRate),
+        };
+        if period.is_zero() {
+            return Err(GcrCreationError::ZeroEmissionInterval);
+        }
+
+        // The max burst is either given directly, as a duration, or defaults to the rate
+        let max_burst = match (self.burst, self.burst_duration, self.rate) {
+            (Some(_), Some(_), _) => return Err(GcrCreationError::ConflictingBurst),
+            (Some(burst), None, _) => burst,
+            (None, Some(burst_duration), _) => {
+                let max_burst = burst_duration
+                    .as_nanos()
+                    .checked_mul(u128::from(rate))
+                    .and_then(|ticks| u64::try_from(ticks / period.as_nanos()).ok())
+                    .ok_or(GcrCreationError::BurstDurationTooLong)?;
+                if max_burst == 0 {
+                    return Err(GcrCreationError::BurstDurationTooShort);
+                }
+                max_burst
+            }
+            (None, None, Some(rate)) => rate,
+            (None, None, None) => return Err(GcrCreationError::MissingBurst),
+        };
+
+        // By default, we instantly have our full burst
+        let initial_capacity = self.initial_capacity.unwrap_or(max_burst);
+        if initial_capacity > max_burst {
+            return Err(GcrCreationError::InitialCapacityTooLarge {
+                initial_capacity,
+                max_burst,
+            });
+        }
+
+        let params = Params::new(rate, period, Some(max_burst))?;
+
+        // The theoretical arrival time is far enough in the future to only leave the initial
+        // capacity available
+        let theoretical_arrival_time = params
+            .emission_interval()
+            .checked_mul(u128::from(max_burst - initial_capacity))
+            .and_then(|ticks| params.ticks(self.clock.now()).checked_add(ticks))
+            .ok_or(GcrCreationError::InstantOverflow {
+                field: InstantField::TheoreticalArrivalTime,
+            })?;
+
+        Ok(Gcr {
+            params,
+            theoretical_arrival_time,
+            max_wait: self.max_wait,
+            max_debt: self.max_debt,
+            clock: self.clock,
+            observer: (),
+        })
+    }
+}
@@ -0,0 +1,278 @@
+//! Clock abstractions used by [`Gcr`](crate::Gcr) to get the current time.
+
+use core::{
+    ops::{Add, Sub},
+    time::Duration,
+};
+#[cfg(feature = "std")]
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, OnceLock,
+    },
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// A point in time, in nanoseconds since an arbitrary origin chosen by the [`Clock`].
+///
+/// Timestamps are only comparable if they come from the same origin. With the `std` feature,
+/// [`Instant`]s can be converted to and from timestamps relative to a process-wide origin, which
+/// is the one used by [`SystemClock`] and [`MockClock`]. It is placed before any [`Instant`] the
+/// process could have observed, so every instant converts exactly regardless of when the first
+/// conversion happens.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Timestamp(u64);
+
+impl Timestamp {
+    /// Create a [`Timestamp`] from a number of nanoseconds since the origin
+    pub const fn from_nanos(nanos: u64) -> Self {
+        Self(nanos)
+    }
+
+    /// Get the number of nanoseconds since the origin
+    pub const fn as_nanos(self) -> u64 {
+        self.0
+    }
+
+    /// Add `duration`, returning `None` if the result does not fit
+    pub fn checked_add(self, duration: Duration) -> Option<Self> {
+        u64::try_from(duration.as_nanos())
+            .ok()
+            .and_then(|nanos| self.0.checked_add(nanos))
+            .map(Self)
+    }
+
+    /// Subtract `duration`, returning `None` if the result would be before the origin
+    pub fn checked_sub(self, duration: Duration) -> Option<Self> {
+        u64::try_from(duration.as_nanos())
+            .ok()
+            .and_then(|nanos| self.0.checked_sub(nanos))
+            .map(Self)
+    }
+
+    /// Add `duration`, saturating at the latest representable time
+    pub fn saturating_add(self, duration: Duration) -> Self {
+        self.checked_add(duration).unwrap_or(Self(u64::MAX))
+    }
+
+    /// Get the amount of time since `earlier`, or `None` if `earlier` is later than `self`
+    pub fn checked_duration_since(self, earlier: Self) -> Option<Duration> {
+        self.0.checked_sub(earlier.0).map(Duration::from_nanos)
+    }
+
+    /// Get the amount of time since `earlier`, or zero if `earlier` is later than `self`
+    pub fn saturating_duration_since(self, earlier: Self) -> Duration {
+        Duration::from_nanos(self.0.saturating_sub(earlier.0))
+    }
+}
+
+/// Panics if the result does not fit. See [`Timestamp::checked_add`]
+impl Add<Duration> for Timestamp {
+    type Output = Self;
+
+    fn add(self, duration: Duration) -> Self {
+        self.checked_add(duration)
+            .expect("overflow when adding duration to timestamp")
+    }
+}
+
+/// Panics if the result would be before the origin. See [`Timestamp::checked_sub`]
+impl Sub<Duration> for Timestamp {
+    type Output = Self;
+
+    fn sub(self, duration: Duration) -> Self {
+        self.checked_sub(duration)
+            .expect("overflow when subtracting duration from timestamp")
+    }
+}
+
+/// How far before the first conversion the process-wide origin is placed, which is longer than
+/// any [`Instant`] the process could have observed before it
+#[cfg(feature = "std")]
+const MAX_ORIGIN_OFFSET: Duration = Duration::from_secs(1 << 30);
+
+/// The process-wide origin for timestamps converted from [`Instant`]s.
+///
+/// This is [`MAX_ORIGIN_OFFSET`] before the first conversion, or the earliest [`Instant`] the
+/// platform can represent if that is later, so instants taken before the first conversion still
+/// convert exactly.
+#[cfg(feature = "std")]
+fn origin() -> Instant {
+    static ORIGIN: OnceLock<Instant> = OnceLock::new();
+    *ORIGIN.get_or_init(|| {
+        let now = Instant::now();
+
+        // Find the largest offset the platform can subtract, since some only represent instants
+        // after boot
+        let (mut low, mut high) = (0, MAX_ORIGIN_OFFSET.as_nanos() as u64);
+        while low < high {
+            let mid = low + (high - low).div_ceil(2);
+            if now.checked_sub(Duration::from_nanos(mid)).is_some() {
+                low = mid;
+            } else {
+                high = mid - 1;
+            }
+        }
+        now - Duration::from_nanos(low)
+    })
+}
+
+/// [`Instant`]s from before the process-wide origin are treated as the origin
+#[cfg(feature = "std")]
+impl From<Instant> for Timestamp {
+    fn from(instant: Instant) -> Self {
+        let nanos = instant.saturating_duration_since(origin()).as_nanos();
+        Self(u64::try_from(nanos).unwrap_or(u64::MAX))
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<Timestamp> for Instant {
+    fn from(timestamp: Timestamp) -> Self {
+        origin() + Duration::from_nanos(timestamp.0)
+    }
+}
+
+/// A source of the current time for a rate limiter.
+///
+/// Any `Fn() -> Timestamp` is a [`Clock`], which makes it easy to read the time from a hardware
+/// timer without the `std` feature.
+pub trait Clock {
+    /// Get the current time
+    fn now(&self) -> Timestamp;
+
+    /// Block the current thread until `duration` has passed on this clock.
+    ///
+    /// Defaults to [`std::thread::sleep`] with the `std` feature, and to spinning on
+    /// [`Clock::now`] without it.
+    fn sleep(&self, duration: Duration) {
+        #[cfg(feature = "std")]
+        std::thread::sleep(duration);
+
+        #[cfg(not(feature = "std"))]
+        {
+            let deadline = self.now().saturating_add(duration);
+            while self.now() < deadline {
+                core::hint::spin_loop();
+            }
+        }
+    }
+}
+
+impl<F: Fn() -> Timestamp> Clock for F {
+    fn now(&self) -> Timestamp {
+        self()
+    }
+}
+
+/// The [`Clock`] used when none is specified: [`SystemClock`] with the `std` feature
+#[cfg(feature = "std")]
+pub type DefaultClock = SystemClock;
+
+/// The [`Clock`] used when none is specified: a function returning the current [`Timestamp`]
+/// without the `std` feature
+#[cfg(not(feature = "std"))]
+pub type DefaultClock = fn() -> Timestamp;
+
+/// A [`Clock`] backed by [`Instant::now`]
+#[cfg(feature = "std")]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SystemClock;
+
+#[cfg(feature = "std")]
+impl Clock for SystemClock {
+    fn now(&self) -> Timestamp {
+        Instant::now().into()
+    }
+}
+
+/// A [`Clock`] backed by [`SystemTime::now`], counting nanoseconds since the Unix epoch.
+///
+/// Unlike the [`SystemClock`], whose origin is only shared within a process, every process (on
+/// any machine with a synchronized clock) agrees on this origin. This makes it suitable for state
+/// shared between processes, such as a [`GcrStore`](crate::GcrStore). The time can jump if the
+/// system clock is changed, and times before the Unix epoch are treated as the epoch.
+#[cfg(feature = "std")]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct WallClock;
+
+#[cfg(feature = "std")]
+impl Clock for WallClock {
+    fn now(&self) -> Timestamp {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+        Timestamp(u64::try_from(nanos).unwrap_or(u64::MAX))
+    }
+}
+
+/// A [`Clock`] that only moves forward when it is explicitly advanced.
+///
+/// Clones share the same underlying time, so a clone can be handed to a [`Gcr`](crate::Gcr)
+/// while the original is used to advance it.
+#[cfg(feature = "std")]
+#[derive(Clone, Debug)]
+pub struct MockClock {
+    /// The time at which the clock was created
+    start: Timestamp,
+    /// The number of nanoseconds the clock has been advanced by
+    elapsed: Arc<AtomicU64>,
+}
+
+#[cfg(feature = "std")]
+impl MockClock {
+    /// Create a new [`MockClock`] starting at the current time
+    pub fn new() -> Self {
+        Self {
+            start: SystemClock.now(),
+            elapsed: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Advance the clock by `duration`
+    pub fn advance(&self, duration: Duration) {
+        // Saturate instead of wrapping so the clock never moves backwards
+        let nanos = u64::try_from(duration.as_nanos()).unwrap_or(u64::MAX);
+        let _ = self
+            .elapsed
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |elapsed| {
+                Some(elapsed.saturating_add(nanos))
+            });
+    }
+
+    /// Get the total amount of time the clock has been advanced by
+    pub fn elapsed(&self) -> Duration {
+        Duration::from_nanos(self.elapsed.load(Ordering::Acquire))
+    }
+}
+
+/// Two [`MockClock`]s are equal if they are clones of each other
+#[cfg(feature = "std")]
+impl PartialEq for MockClock {
+    fn eq(&self, other: &Self) -> bool {
+        self.start == other.start && Arc::ptr_eq(&self.elapsed, &other.elapsed)
+    }
+}
+
+#[cfg(feature = "std")]
+impl Eq for MockClock {}
+
+#[cfg(feature = "std")]
+impl Default for MockClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(feature = "std")]
+impl Clock for MockClock {
+    fn now(&self) -> Timestamp {
+        self.start.saturating_add(self.elapsed())
+    }
+
+    /// Advance the clock by `duration` instead of blocking
+    fn sleep(&self, duration: Duration) {
+        self.advance(duration);
+    }
+}
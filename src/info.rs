@@ -0,0 +1,134 @@
+//! Describing the state of a [`Gcr`] to clients, such as with `RateLimit` HTTP headers.
+
+use core::time::Duration;
+
+use crate::{Clock, Gcr, GcrObserver, GcrRequestError, Timestamp};
+
+/// The name of the header with the maximum number of units available at once
+pub const RATE_LIMIT_LIMIT: &str = "ratelimit-limit";
+/// The name of the header with the number of units currently available
+pub const RATE_LIMIT_REMAINING: &str = "ratelimit-remaining";
+/// The name of the header with the number of seconds until every unit is available again
+pub const RATE_LIMIT_RESET: &str = "ratelimit-reset";
+/// The name of the header with the number of seconds until a denied request would be allowed
+pub const RETRY_AFTER: &str = "retry-after";
+
+/// Round a duration up to whole seconds, as header values must not promise more than is available
+fn secs_ceil(duration: Duration) -> u64 {
+    duration
+        .as_secs()
+        .saturating_add(u64::from(duration.subsec_nanos() > 0))
+}
+
+/// The state of a [`Gcr`] at a point in time, created with [`Gcr::rate_limit_info`].
+///
+/// This contains everything needed for the `RateLimit-Limit`, `RateLimit-Remaining`,
+/// `RateLimit-Reset` and `Retry-After` headers described by the IETF RateLimit header fields
+/// draft. Header values are in whole seconds, rounded up, so clients that wait as long as they
+/// are told to are never denied because of rounding.
+///
+#[cfg_attr(feature = "std", doc = "```rust")]
+#[cfg_attr(not(feature = "std"), doc = "```rust,ignore")]
+/// use gcr::Gcr;
+/// use std::time::Duration;
+///
+/// let mut rate = Gcr::new(10, Duration::from_secs(1), Some(30)).unwrap();
+///
+/// match rate.request(20) {
+///     Ok(()) => {
+///         for (name, value) in rate.rate_limit_info().allowed_headers() {
+///             println!("{name}: {value}"); // Add the header to the response
+///         }
+///     }
+///     Err(error) => {
+///         for (name, value) in rate.rate_limit_info().denied_headers(&error) {
+///             println!("{name}: {value}"); // Add the header to the 429 response
+///         }
+///     }
+/// }
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RateLimitInfo {
+    /// The maximum number of units available at once
+    limit: u64,
+    /// The number of units currently available
+    remaining: u64,
+    /// How long until every unit is available again
+    reset_after: Duration,
+    /// How long until at least one unit is available
+    retry_after: Duration,
+}
+
+impl RateLimitInfo {
+    /// Get the maximum number of units available at once, which is the max burst
+    pub fn limit(&self) -> u64 {
+        self.limit
+    }
+
+    /// Get the number of units currently available
+    pub fn remaining(&self) -> u64 {
+        self.remaining
+    }
+
+    /// Get how long until every unit is available again, or zero if they already are
+    pub fn reset_after(&self) -> Duration {
+        self.reset_after
+    }
+
+    /// Get how long until at least one unit is available, or zero if one already is
+    pub fn retry_after(&self) -> Duration {
+        self.retry_after
+    }
+
+    /// Get the headers for a response to a request that was allowed, as lowercase names and
+    /// values in whole units or seconds: `RateLimit-Limit`, `RateLimit-Remaining`, and
+    /// `RateLimit-Reset`
+    pub fn allowed_headers(&self) -> [(&'static str, u64); 3] {
+        [
+            (RATE_LIMIT_LIMIT, self.limit),
+            (RATE_LIMIT_REMAINING, self.remaining),
+            (RATE_LIMIT_RESET, secs_ceil(self.reset_after)),
+        ]
+    }
+
+    /// Get the headers for a response to a request that failed with `error`: the
+    /// [allowed headers](RateLimitInfo::allowed_headers) followed by `Retry-After`.
+    ///
+    /// `Retry-After` is taken from [`GcrRequestError::DeniedFor`], so it accounts for the size of
+    /// the request. It is left out for requests that can never be allowed, such as
+    /// [`GcrRequestError::RequestTooLarge`].
+    pub fn denied_headers(
+        &self,
+        error: &GcrRequestError,
+    ) -> impl Iterator<Item = (&'static str, u64)> {
+        let retry_after = match error {
+            GcrRequestError::DeniedFor(duration) => Some((RETRY_AFTER, secs_ceil(*duration))),
+            _ => None,
+        };
+
+        self.allowed_headers().into_iter().chain(retry_after)
+    }
+}
+
+impl<C: Clock, O: GcrObserver> Gcr<C, O> {
+    /// Get the [`RateLimitInfo`] of the rate limiter at a given time.
+    ///
+    /// Note: this function calculates the info on the fly
+    pub fn rate_limit_info_at(&self, now: impl Into<Timestamp>) -> RateLimitInfo {
+        let now = now.into();
+        RateLimitInfo {
+            limit: self.params.max_burst,
+            remaining: self.params.capacity(self.theoretical_arrival_time, now),
+            reset_after: self.params.until(self.theoretical_arrival_time, now),
+            retry_after: self.params.next_unit(self.theoretical_arrival_time, now),
+        }
+    }
+
+    /// Get the current [`RateLimitInfo`] of the rate limiter, such as to build `RateLimit`
+    /// headers for a response
+    ///
+    /// Note: this function calculates the info on the fly
+    pub fn rate_limit_info(&self) -> RateLimitInfo {
+        self.rate_limit_info_at(self.clock.now())
+    }
+}
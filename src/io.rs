@@ -0,0 +1,226 @@
+//! Limiting the bandwidth of [`Read`] and [`Write`] streams, one unit per byte.
+
+use std::{
+    io::{self, Read, Write},
+    sync::{Arc, Mutex},
+};
+
+use crate::{lock, Clock, Gcr, GcrRequestError, SystemClock};
+
+/// The limiter shared by throttled streams, along with a copy of its clock to sleep on without
+/// holding the lock
+#[derive(Clone, Debug)]
+struct Throttle<C: Clock> {
+    /// The limiter that every byte is requested from
+    limiter: Arc<Mutex<Gcr<C>>>,
+    /// A copy of the limiter's clock
+    clock: C,
+}
+
+impl<C: Clock + Clone> Throttle<C> {
+    /// Create a new [`Throttle`] that requests bytes from `limiter`
+    fn new(limiter: Arc<Mutex<Gcr<C>>>) -> Self {
+        let clock = lock(&limiter).clock().clone();
+        Self { limiter, clock }
+    }
+
+    /// Get how many of `len` bytes to transfer at once. This is at most the maximum burst, so
+    /// large buffers are split into several requests instead of failing with
+    /// [`GcrRequestError::RequestTooLarge`], which is only returned if no bytes can ever be
+    /// transferred
+    fn chunk(&self, len: usize) -> io::Result<usize> {
+        match lock(&self.limiter).params.max_burst {
+            0 => Err(io::Error::other(GcrRequestError::RequestTooLarge)),
+            max_burst => Ok(len.min(usize::try_from(max_burst).unwrap_or(usize::MAX))),
+        }
+    }
+
+    /// Consume `n` bytes, sleeping until they are available
+    fn wait(&self, n: usize) -> io::Result<()> {
+        // Reserve the bytes and release the lock before sleeping, so other streams sharing the
+        // limiter can queue up behind us in the meantime
+        let wait = lock(&self.limiter)
+            .reserve_within(n as u64, self.clock.now(), None)
+            .map_err(io::Error::other)?;
+        if !wait.is_zero() {
+            self.clock.sleep(wait);
+        }
+
+        Ok(())
+    }
+
+    /// Return the `n` bytes that were consumed but not transferred
+    fn release(&self, n: usize) {
+        if n > 0 {
+            lock(&self.limiter).release(n as u64);
+        }
+    }
+}
+
+/// A [`Read`] wrapper that limits how many bytes per second are read, by requesting one unit
+/// from a [`Gcr`] for every byte.
+///
+/// Each read is limited to the maximum burst, and blocks with [`Clock::sleep`] after reading
+/// until the bytes that were read are available, so only bytes that were actually read count
+/// towards the limit. Several streams can share one limit with
+/// [`ThrottledReader::shared`].
+///
+/// ```rust
+/// use gcr::{Gcr, ThrottledReader};
+/// use std::{io::Read, time::Duration};
+///
+/// let rate = Gcr::new(1024 * 1024, Duration::from_secs(1), None).unwrap();
+/// let mut reader = ThrottledReader::new(&b"hello"[..], rate); // 1 MiB/s
+///
+/// let mut buf = String::new();
+/// reader.read_to_string(&mut buf).unwrap();
+/// assert_eq!(buf, "hello");
+/// ```
+#[derive(Clone, Debug)]
+pub struct ThrottledReader<R, C: Clock = SystemClock> {
+    /// The stream being read from
+    inner: R,
+    /// Where the bytes are requested from
+    throttle: Throttle<C>,
+}
+
+impl<R, C: Clock + Clone> ThrottledReader<R, C> {
+    /// Create a new [`ThrottledReader`] that limits reads from `inner` with `gcr`
+    pub fn new(inner: R, gcr: Gcr<C>) -> Self {
+        Self::shared(inner, Arc::new(Mutex::new(gcr)))
+    }
+
+    /// Create a new [`ThrottledReader`] that limits reads from `inner` with a `limiter` that may
+    /// also be used by other streams, such as the [limiter](ThrottledReader::limiter) of another
+    /// [`ThrottledReader`] or [`ThrottledWriter`]
+    pub fn shared(inner: R, limiter: Arc<Mutex<Gcr<C>>>) -> Self {
+        Self {
+            inner,
+            throttle: Throttle::new(limiter),
+        }
+    }
+
+    /// Get the shared [`Gcr`], such as to share it with another stream or to
+    /// [adjust](Gcr::adjust) it while reading
+    pub fn limiter(&self) -> &Arc<Mutex<Gcr<C>>> {
+        &self.throttle.limiter
+    }
+
+    /// Get a reference to the stream being read from
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    /// Get a mutable reference to the stream being read from. Reading from it directly bypasses
+    /// the limit
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.inner
+    }
+
+    /// Unwrap the stream being read from
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: Read, C: Clock + Clone> Read for ThrottledReader<R, C> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return self.inner.read(buf);
+        }
+
+        // The bytes are only paid for once they have been read, so reaching the end of the
+        // stream never waits
+        let n = self.throttle.chunk(buf.len())?;
+        let read = self.inner.read(&mut buf[..n])?;
+        if read > 0 {
+            self.throttle.wait(read.min(n))?;
+        }
+
+        Ok(read)
+    }
+}
+
+/// A [`Write`] wrapper that limits how many bytes per second are written, by requesting one unit
+/// from a [`Gcr`] for every byte.
+///
+/// Each write is limited to the maximum burst and blocks with [`Clock::sleep`] until its bytes
+/// are available, so [`Write::write_all`] transfers large buffers in chunks. Bytes that were
+/// requested but not written are [released](Gcr::release) again. Several streams can share one
+/// limit with [`ThrottledWriter::shared`].
+///
+/// ```rust
+/// use gcr::{Gcr, ThrottledWriter};
+/// use std::{io::Write, time::Duration};
+///
+/// let rate = Gcr::new(1024 * 1024, Duration::from_secs(1), None).unwrap();
+/// let mut writer = ThrottledWriter::new(Vec::new(), rate); // 1 MiB/s
+///
+/// writer.write_all(b"hello").unwrap();
+/// assert_eq!(writer.get_ref(), b"hello");
+/// ```
+#[derive(Clone, Debug)]
+pub struct ThrottledWriter<W, C: Clock = SystemClock> {
+    /// The stream being written to
+    inner: W,
+    /// Where the bytes are requested from
+    throttle: Throttle<C>,
+}
+
+impl<W, C: Clock + Clone> ThrottledWriter<W, C> {
+    /// Create a new [`ThrottledWriter`] that limits writes to `inner` with `gcr`
+    pub fn new(inner: W, gcr: Gcr<C>) -> Self {
+        Self::shared(inner, Arc::new(Mutex::new(gcr)))
+    }
+
+    /// Create a new [`ThrottledWriter`] that limits writes to `inner` with a `limiter` that may
+    /// also be used by other streams, such as the [limiter](ThrottledWriter::limiter) of another
+    /// [`ThrottledWriter`] or [`ThrottledReader`]
+    pub fn shared(inner: W, limiter: Arc<Mutex<Gcr<C>>>) -> Self {
+        Self {
+            inner,
+            throttle: Throttle::new(limiter),
+        }
+    }
+
+    /// Get the shared [`Gcr`], such as to share it with another stream or to
+    /// [adjust](Gcr::adjust) it while writing
+    pub fn limiter(&self) -> &Arc<Mutex<Gcr<C>>> {
+        &self.throttle.limiter
+    }
+
+    /// Get a reference to the stream being written to
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    /// Get a mutable reference to the stream being written to. Writing to it directly bypasses
+    /// the limit
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+
+    /// Unwrap the stream being written to
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+impl<W: Write, C: Clock + Clone> Write for ThrottledWriter<W, C> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return self.inner.write(buf);
+        }
+
+        let n = self.throttle.chunk(buf.len())?;
+        self.throttle.wait(n)?;
+        let result = self.inner.write(&buf[..n]);
+        self.throttle
+            .release(n - result.as_ref().map_or(0, |written| (*written).min(n)));
+        result
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
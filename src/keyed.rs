@@ -0,0 +1,367 @@
+//! A rate limiter that keeps a separate [`Gcr`] per key.
+
+use std::{
+    borrow::Borrow,
+    cmp::max,
+    collections::{hash_map, BTreeMap, HashMap},
+    hash::Hash,
+    time::Duration,
+};
+
+use crate::{
+    Clock, Gcr, GcrCreationError, GcrObserver, GcrRequestError, Params, SystemClock, Timestamp,
+};
+
+/// A collection of [`Gcr`] instances, one per key, that share the same configuration.
+///
+/// A [`Gcr`] is created for a key the first time units are requested for it. Until then, the key
+/// is treated as having its full burst available.
+///
+/// Keys whose [`Gcr`] has fully refilled with the shared parameters can be dropped with
+/// [`KeyedGcr::retain_recent`] without changing any limiting decisions. A hard limit on the number of keys can be set with
+/// [`KeyedGcr::with_max_keys`].
+#[derive(Clone, Debug)]
+pub struct KeyedGcr<K, C: Clock = SystemClock, O: GcrObserver = ()> {
+    /// The instance new keys are cloned from
+    template: Gcr<C, O>,
+    /// The instances for each key that has made a request
+    limiters: HashMap<K, Entry<C, O>>,
+    /// Every key ordered by the value of `tick` when it was last used, least recently used first
+    order: BTreeMap<u64, K>,
+    /// The maximum number of keys to keep before evicting the least recently used one
+    max_keys: Option<usize>,
+    /// The number of keys inserted at `max_keys` since idle keys were last swept
+    since_sweep: usize,
+    /// Incremented on every use of a key, used to find the least recently used key
+    tick: u64,
+}
+
+/// A [`Gcr`] along with when it was last used
+#[derive(Clone, Debug)]
+struct Entry<C: Clock, O: GcrObserver> {
+    gcr: Gcr<C, O>,
+    /// The value of [`KeyedGcr::tick`] when this key was last used
+    last_used: u64,
+}
+
+impl<K: Hash + Eq> KeyedGcr<K> {
+    /// Create a new [`KeyedGcr`] instance that uses the [`SystemClock`].
+    ///
+    /// Accepts the same parameters as [`Gcr::new`], which are used for every key.
+    ///
+    /// # Errors
+    /// - [`GcrCreationError::ZeroRate`] - if the rate was zero
+    /// - [`GcrCreationError::ZeroEmissionInterval`] - if the period was zero
+    /// - [`GcrCreationError::DelayToleranceOverflow`] - if the parameters are out of range
+    pub fn new(
+        rate: u64,
+        period: Duration,
+        max_burst: Option<u64>,
+    ) -> Result<Self, GcrCreationError> {
+        Self::with_clock(rate, period, max_burst, SystemClock)
+    }
+}
+
+impl<K: Hash + Eq, C: Clock + Clone> KeyedGcr<K, C> {
+    /// Create a new [`KeyedGcr`] instance that reads the current time from `clock`.
+    ///
+    /// Accepts the same parameters as [`Gcr::new`], which are used for every key.
+    ///
+    /// # Errors
+    /// - [`GcrCreationError::ZeroRate`] - if the rate was zero
+    /// - [`GcrCreationError::ZeroEmissionInterval`] - if the period was zero
+    /// - [`GcrCreationError::DelayToleranceOverflow`] - if the parameters are out of range
+    pub fn with_clock(
+        rate: u64,
+        period: Duration,
+        max_burst: Option<u64>,
+        clock: C,
+    ) -> Result<Self, GcrCreationError> {
+        Ok(Self {
+            template: Gcr::with_clock(rate, period, max_burst, clock)?,
+            limiters: HashMap::new(),
+            order: BTreeMap::new(),
+            max_keys: None,
+            since_sweep: usize::MAX,
+            tick: 0,
+        })
+    }
+}
+
+impl<K: Hash + Eq + Clone, C: Clock + Clone, O: GcrObserver + Clone> KeyedGcr<K, C, O> {
+    /// Attach a [`GcrObserver`] that is called on every decision for every key, replacing any
+    /// existing one.
+    ///
+    /// Each key gets its own clone of the observer, so attach a reference or an
+    /// [`Arc`](std::sync::Arc) to share one between keys.
+    pub fn with_observer<P: GcrObserver + Clone>(self, observer: P) -> KeyedGcr<K, C, P> {
+        KeyedGcr {
+            template: self.template.with_observer(observer.clone()),
+            limiters: self
+                .limiters
+                .into_iter()
+                .map(|(key, entry)| {
+                    let entry = Entry {
+                        gcr: entry.gcr.with_observer(observer.clone()),
+                        last_used: entry.last_used,
+                    };
+                    (key, entry)
+                })
+                .collect(),
+            order: self.order,
+            max_keys: self.max_keys,
+            since_sweep: self.since_sweep,
+            tick: self.tick,
+        }
+    }
+
+    /// Limit the number of keys kept at once to `max_keys`.
+    ///
+    /// When a new key would exceed the limit, idle keys are dropped first. If that is not enough,
+    /// the least recently used key is evicted and will start from a full burst on its next request.
+    /// A limit of zero behaves like a limit of one.
+    ///
+    /// To keep requests for new keys cheap, the map is only swept for idle keys once every
+    /// `max_keys` new keys. In between, the least recently used key is evicted directly, which
+    /// does not change any decisions if it is idle.
+    pub fn with_max_keys(mut self, max_keys: usize) -> Self {
+        self.max_keys = Some(max(max_keys, 1));
+        self
+    }
+
+    /// Get a reference to the [`Clock`] used by this instance
+    pub fn clock(&self) -> &C {
+        self.template.clock()
+    }
+
+    /// Get the number of keys that currently have their own [`Gcr`]
+    pub fn len(&self) -> usize {
+        self.limiters.len()
+    }
+
+    /// Whether no keys currently have their own [`Gcr`]
+    pub fn is_empty(&self) -> bool {
+        self.limiters.is_empty()
+    }
+
+    /// Get the [`Gcr`] for `key`, if one has been created
+    pub fn get<Q>(&self, key: &Q) -> Option<&Gcr<C, O>>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.limiters.get(key).map(|entry| &entry.gcr)
+    }
+
+    /// Remove the [`Gcr`] for `key`, returning it if one had been created.
+    ///
+    /// The next request for `key` will start from a full burst.
+    pub fn remove<Q>(&mut self, key: &Q) -> Option<Gcr<C, O>>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let entry = self.limiters.remove(key)?;
+        self.order.remove(&entry.last_used);
+        Some(entry.gcr)
+    }
+
+    /// Drop every key whose [`Gcr`] has fully refilled by `now`.
+    ///
+    /// Returns the number of keys dropped.
+    pub fn retain_recent_at(&mut self, now: impl Into<Timestamp>) -> usize {
+        let now = now.into();
+        let len = self.limiters.len();
+        let params = self.template.params;
+        let order = &mut self.order;
+
+        // Keys adjusted with their own parameters are kept, since dropping them would change
+        // their decisions
+        self.limiters.retain(|_, entry| {
+            let droppable = entry.gcr.params == params && entry.gcr.is_idle_at(now);
+            if droppable {
+                order.remove(&entry.last_used);
+            }
+            !droppable
+        });
+        len - self.limiters.len()
+    }
+
+    /// Drop every key whose [`Gcr`] has fully refilled.
+    ///
+    /// Idle keys behave exactly like keys that have never made a request, so this does not
+    /// change any limiting decisions. Keys adjusted with [`KeyedGcr::adjust_key`] are kept, since
+    /// dropping them would bring them back to the shared parameters.
+    ///
+    /// Returns the number of keys dropped.
+    pub fn retain_recent(&mut self) -> usize {
+        let now = self.clock().now();
+        self.retain_recent_at(now)
+    }
+
+    /// Drop every idle key like [`KeyedGcr::retain_recent`] and release the memory they used.
+    ///
+    /// Returns the number of keys dropped.
+    pub fn shrink(&mut self) -> usize {
+        let dropped = self.retain_recent();
+        self.limiters.shrink_to_fit();
+        dropped
+    }
+
+    /// Get the capacity for `key` at a given time.
+    ///
+    /// Note: this function calculates the capacity on the fly
+    pub fn capacity_at<Q>(&self, key: &Q, now: impl Into<Timestamp>) -> u64
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.get(key).unwrap_or(&self.template).capacity_at(now)
+    }
+
+    /// Get the current capacity for `key`
+    ///
+    /// Note: this function calculates the capacity on the fly
+    pub fn capacity<Q>(&self, key: &Q) -> u64
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.capacity_at(key, self.clock().now())
+    }
+
+    /// Get the [`Gcr`] for `key`, creating it (and making room for it) if it does not exist yet
+    pub(crate) fn limiter_at(&mut self, key: K, now: Timestamp) -> &mut Gcr<C, O> {
+        self.tick += 1;
+
+        // Make room for the new key if we are at the limit
+        if let Some(max_keys) = self.max_keys {
+            if self.limiters.len() >= max_keys && !self.limiters.contains_key(&key) {
+                self.make_room(max_keys, now);
+            }
+        }
+
+        // Move the key to the most recently used end of the order
+        let tick = self.tick;
+        match self.limiters.entry(key) {
+            hash_map::Entry::Occupied(entry) => {
+                let entry = entry.into_mut();
+                if let Some(key) = self.order.remove(&entry.last_used) {
+                    self.order.insert(tick, key);
+                }
+                entry.last_used = tick;
+                &mut entry.gcr
+            }
+            hash_map::Entry::Vacant(entry) => {
+                self.order.insert(tick, entry.key().clone());
+                &mut entry
+                    .insert(Entry {
+                        gcr: self.template.clone(),
+                        last_used: tick,
+                    })
+                    .gcr
+            }
+        }
+    }
+
+    /// Drop keys until there is room for a new one under `max_keys`
+    fn make_room(&mut self, max_keys: usize, now: Timestamp) {
+        // Prefer dropping idle keys, since that does not change any decisions. Sweeping every key
+        // is only done once every `max_keys` new keys, so the cost is spread across them
+        self.since_sweep = self.since_sweep.saturating_add(1);
+        if self.since_sweep >= max_keys {
+            self.since_sweep = 0;
+            self.retain_recent_at(now);
+        }
+
+        // Otherwise, evict the least recently used keys
+        while self.limiters.len() >= max_keys {
+            let Some((_, key)) = self.order.pop_first() else {
+                break;
+            };
+            self.limiters.remove(&key);
+        }
+    }
+
+    /// Request `n` units for `key`, creating its [`Gcr`] if it does not exist yet.
+    ///
+    /// # Errors
+    /// - See [`Gcr::request`]
+    pub fn request(&mut self, key: K, n: u64) -> Result<(), GcrRequestError> {
+        let now = self.clock().now();
+        self.request_at(key, n, now)
+    }
+
+    /// Request `n` units for `key` as if the request was made at `now`.
+    ///
+    /// # Errors
+    /// - See [`Gcr::request`]
+    pub fn request_at(
+        &mut self,
+        key: K,
+        n: u64,
+        now: impl Into<Timestamp>,
+    ) -> Result<(), GcrRequestError> {
+        let now = now.into();
+        self.limiter_at(key, now).request_at(n, now)
+    }
+
+    /// Adjust the parameters of every key while preserving their current capacity.
+    ///
+    /// Keys created after this call also use the new parameters. If an error is returned, no key
+    /// is changed.
+    ///
+    /// # Errors
+    /// - [`GcrCreationError::ZeroRate`] - if the rate was zero
+    /// - [`GcrCreationError::ZeroEmissionInterval`] - if the period was zero
+    /// - [`GcrCreationError::DelayToleranceOverflow`] or [`GcrCreationError::InstantOverflow`] - if the parameters are out of range
+    pub fn adjust_all(
+        &mut self,
+        rate: u64,
+        period: Duration,
+        max_burst: Option<u64>,
+    ) -> Result<(), GcrCreationError> {
+        // This is the canonical adjustment time
+        let now = self.clock().now();
+
+        // Check the parameters and every key before updating any, so a failure changes nothing
+        let params = Params::new(rate, period, max_burst)?;
+        for entry in self.limiters.values() {
+            entry
+                .gcr
+                .params
+                .rebase(entry.gcr.theoretical_arrival_time, now, &params)?;
+        }
+
+        // The template is rebuilt rather than adjusted, so new keys always start with the full
+        // new burst
+        self.template.params = params;
+        self.template.theoretical_arrival_time = params.ticks(now);
+        for entry in self.limiters.values_mut() {
+            entry.gcr.adjust_at(rate, period, max_burst, now)?;
+        }
+
+        Ok(())
+    }
+
+    /// Adjust the parameters of a single key while preserving its current capacity.
+    ///
+    /// Note: the adjustment only lasts as long as the key's [`Gcr`]. If it is removed or
+    /// evicted, the key goes back to the shared parameters.
+    ///
+    /// # Errors
+    /// - [`GcrCreationError::ZeroRate`] - if the rate was zero
+    /// - [`GcrCreationError::ZeroEmissionInterval`] - if the period was zero
+    /// - [`GcrCreationError::DelayToleranceOverflow`] or [`GcrCreationError::InstantOverflow`] - if the parameters are out of range
+    pub fn adjust_key(
+        &mut self,
+        key: K,
+        rate: u64,
+        period: Duration,
+        max_burst: Option<u64>,
+    ) -> Result<(), GcrCreationError> {
+        let now = self.clock().now();
+        self.limiter_at(key, now)
+            .adjust_at(rate, period, max_burst, now)
+    }
+}
@@ -3,7 +3,8 @@
 //!
 //! # Usage
 //!
-//! ```rust
+#![cfg_attr(feature = "std", doc = "```rust")]
+#![cfg_attr(not(feature = "std"), doc = "```rust,ignore")]
 //! use gcr::Gcr;
 //! use std::time::Duration;
 //!
@@ -20,7 +21,8 @@
 //!
 //! It accepts the same parameters as [`Gcr::new`].
 //!
-//! ```rust
+#![cfg_attr(feature = "std", doc = "```rust")]
+#![cfg_attr(not(feature = "std"), doc = "```rust,ignore")]
 //! use gcr::Gcr;
 //! use std::time::Duration;
 //!
@@ -33,38 +35,588 @@
 //! ## Capacity
 //!
 //! [`Gcr::capacity`] can be used to get the current capacity of the rate limiter without making a request.
+//!
+//! ## Builder
+//!
+//! [`Gcr::builder`] allows for more configuration than [`Gcr::new`], such as an explicit emission
+//! interval, a max burst specified as a duration, or starting empty rather than full.
+//!
+#![cfg_attr(feature = "std", doc = "```rust")]
+#![cfg_attr(not(feature = "std"), doc = "```rust,ignore")]
+//! use gcr::Gcr;
+//! use std::time::Duration;
+//!
+//! let mut rate = Gcr::builder()
+//!     .emission_interval(Duration::from_millis(100))
+//!     .burst_duration(Duration::from_secs(3))
+//!     .initial_capacity(10)
+//!     .build()
+//!     .unwrap();
+//!     // 10 units allowed every second with a max burst of 30 units, starting with 10
+//!
+//! rate.request(10).unwrap();
+//! rate.request(1).unwrap_err();
+//! ```
+//!
+//! ## Checking without consuming
+//!
+//! [`Gcr::check`] runs the same checks as [`Gcr::request`] without consuming any units, and
+//! [`Gcr::time_until`] returns how long until a request would be allowed, so work can be planned
+//! across several limiters before committing to one.
+//!
+#![cfg_attr(feature = "std", doc = "```rust")]
+#![cfg_attr(not(feature = "std"), doc = "```rust,ignore")]
+//! use gcr::Gcr;
+//! use std::time::Duration;
+//!
+//! let mut rate = Gcr::new(10, Duration::from_secs(1), Some(30)).unwrap();
+//!
+//! rate.request(25).unwrap();
+//! rate.check(10).unwrap_err(); // Nothing is consumed
+//! let wait = rate.time_until(10).unwrap(); // About 500ms
+//! assert_eq!(rate.time_until(31), None); // Can never be allowed
+//! ```
+//!
+//! ## Refunds
+//!
+//! [`Gcr::release`] gives previously granted units back, such as when the work they were
+//! requested for failed. [`Gcr::request_guarded`] returns a [`Permit`] that does this
+//! automatically when dropped, unless it is committed. For a [`Gcr`] shared behind a `Mutex`,
+//! [`Gcr::request_shared`] returns a [`SharedPermit`] that only locks it while requesting and
+//! refunding, so other requests are not blocked while the work is done.
+//!
+#![cfg_attr(feature = "std", doc = "```rust")]
+#![cfg_attr(not(feature = "std"), doc = "```rust,ignore")]
+//! use gcr::Gcr;
+//! use std::time::Duration;
+//!
+//! let mut rate = Gcr::new(10, Duration::from_secs(1), Some(30)).unwrap();
+//!
+//! rate.request(20).unwrap();
+//! rate.release(5); // Capacity is now 15
+//!
+//! let permit = rate.request_guarded(10).unwrap();
+//! permit.commit(); // Keep the units. Dropping the permit would have refunded them
+//!
+//! let rate = std::sync::Arc::new(std::sync::Mutex::new(rate));
+//! let permit = Gcr::request_shared(rate.clone(), 5).unwrap(); // The lock is already released
+//! drop(permit); // Refund the units, locking only to do so
+//! ```
+//!
+//! ## Charging after the fact
+//!
+//! [`Gcr::charge`] consumes units unconditionally, for work whose cost is only known once it is
+//! done. Units beyond the capacity are debt: requests are denied until enough time has passed to
+//! pay it off. [`Gcr::with_max_debt`] bounds how far into debt the rate limiter can go.
+//!
+#![cfg_attr(feature = "std", doc = "```rust")]
+#![cfg_attr(not(feature = "std"), doc = "```rust,ignore")]
+//! use gcr::Gcr;
+//! use std::time::Duration;
+//!
+//! let mut rate = Gcr::new(10, Duration::from_secs(1), Some(30))
+//!     .unwrap()
+//!     .with_max_debt(20);
+//!
+//! rate.request(1).unwrap(); // Admit the work before its cost is known
+//! assert_eq!(rate.charge(60), 49); // Only 49 units fit within the max debt
+//! rate.request(1).unwrap_err(); // Denied until the debt is paid off
+//! ```
+//!
+//! ## Partial requests
+//!
+//! [`Gcr::request_up_to`] grants as many of the requested units as are currently available,
+//! instead of denying the whole request.
+//!
+#![cfg_attr(feature = "std", doc = "```rust")]
+#![cfg_attr(not(feature = "std"), doc = "```rust,ignore")]
+//! use gcr::Gcr;
+//! use std::time::Duration;
+//!
+//! let mut rate = Gcr::new(10, Duration::from_secs(1), Some(30)).unwrap();
+//!
+//! assert_eq!(rate.request_up_to(20), 20);
+//! assert_eq!(rate.request_up_to(20), 10); // Only 10 units were left
+//! ```
+//!
+//! ## Clocks
+//!
+//! [`Gcr`] reads the current time from a [`Clock`]. By default this is the [`SystemClock`], but
+//! [`Gcr::with_clock`] accepts any [`Clock`], such as a [`MockClock`] that is advanced manually
+//! for deterministic tests and simulations.
+//!
+#![cfg_attr(feature = "std", doc = "```rust")]
+#![cfg_attr(not(feature = "std"), doc = "```rust,ignore")]
+//! use gcr::{Gcr, MockClock};
+//! use std::time::Duration;
+//!
+//! let clock = MockClock::new();
+//! let mut rate = Gcr::with_clock(10, Duration::from_secs(1), Some(30), clock.clone()).unwrap();
+//!
+//! rate.request(30).unwrap();
+//! clock.advance(Duration::from_secs(2));
+//! assert_eq!(rate.capacity(), 20);
+//! ```
+//!
+//! ## Sharing between threads
+//!
+//! [`AtomicGcr`] has the same semantics as [`Gcr`], but only needs `&self` to make requests.
+//! Its state is updated with a lock-free compare-and-swap loop, so it can be shared between
+//! threads without a `Mutex`.
+//!
+#![cfg_attr(feature = "std", doc = "```rust")]
+#![cfg_attr(not(feature = "std"), doc = "```rust,ignore")]
+//! use gcr::AtomicGcr;
+//! use std::{sync::Arc, time::Duration};
+//!
+//! let rate = Arc::new(AtomicGcr::new(10, Duration::from_secs(1), Some(30)).unwrap());
+//!
+//! let handle = std::thread::spawn({
+//!     let rate = Arc::clone(&rate);
+//!     move || rate.request(20)
+//! });
+//! handle.join().unwrap().unwrap();
+//! assert_eq!(rate.capacity(), 10);
+//! ```
+//!
+//! ## Waiting
+//!
+//! [`Gcr::wait`] reserves the requested units and blocks for exactly as long as required instead
+//! of returning [`GcrRequestError::DeniedFor`]. With the `async` feature enabled, `Gcr::until_ready`
+//! does the same using any async runtime's sleep function.
+//!
+#![cfg_attr(feature = "std", doc = "```rust")]
+#![cfg_attr(not(feature = "std"), doc = "```rust,ignore")]
+//! use gcr::{Gcr, MockClock};
+//! use std::time::Duration;
+//!
+//! let clock = MockClock::new();
+//! let mut rate = Gcr::with_clock(10, Duration::from_secs(1), Some(30), clock.clone()).unwrap();
+//!
+//! rate.wait(30).unwrap();
+//! rate.wait(10).unwrap(); // Advances the mock clock by one second
+//! assert_eq!(clock.elapsed(), Duration::from_secs(1));
+//! ```
+//!
+//! ## Reservations
+//!
+//! [`Gcr::reserve`] consumes units even if they are not available yet, and returns a
+//! [`Reservation`] with the time at which the caller may act on them. [`Gcr::with_max_wait`] limits
+//! how far ahead units can be reserved.
+//!
+#![cfg_attr(feature = "std", doc = "```rust")]
+#![cfg_attr(not(feature = "std"), doc = "```rust,ignore")]
+//! use gcr::Gcr;
+//! use std::time::{Duration, Instant};
+//!
+//! let mut rate = Gcr::new(10, Duration::from_secs(1), Some(30))
+//!     .unwrap()
+//!     .with_max_wait(Duration::from_secs(5));
+//!
+//! rate.reserve(30).unwrap(); // Ready immediately
+//! let reservation = rate.reserve(20).unwrap(); // Ready in 2 seconds
+//! assert!(Instant::from(reservation.ready_at()) > Instant::now());
+//!
+//! reservation.cancel(&mut rate); // Give the units back
+//! ```
+//!
+//! ## Persisting state
+//!
+//! [`Gcr::snapshot`] captures the state of a rate limiter as a [`GcrSnapshot`], which can be
+//! serialized with the `serde` feature. [`Gcr::restore`] rebases it onto the current process,
+//! counting the wall-clock time that passed in between towards refilling it.
+//!
+#![cfg_attr(feature = "std", doc = "```rust")]
+#![cfg_attr(not(feature = "std"), doc = "```rust,ignore")]
+//! use gcr::Gcr;
+//! use std::time::Duration;
+//!
+//! let mut rate = Gcr::new(10, Duration::from_secs(1), Some(30)).unwrap();
+//! rate.request(30).unwrap();
+//!
+//! let snapshot = rate.snapshot();
+//! let mut restored = Gcr::restore(&snapshot).unwrap();
+//! restored.request(10).unwrap_err(); // Clients do not get a free burst
+//! ```
+//!
+//! ## Per-key limits
+//!
+//! [`KeyedGcr`] keeps a separate [`Gcr`] for each key (such as a client ID), all sharing the same
+//! parameters. Each key starts with its full burst the first time it makes a request.
+//!
+#![cfg_attr(feature = "std", doc = "```rust")]
+#![cfg_attr(not(feature = "std"), doc = "```rust,ignore")]
+//! use gcr::KeyedGcr;
+//! use std::time::Duration;
+//!
+//! let mut rate = KeyedGcr::new(10, Duration::from_secs(1), Some(30)).unwrap();
+//!
+//! rate.request("alice", 30).unwrap();
+//! rate.request("bob", 30).unwrap();
+//! rate.request("alice", 30).unwrap_err();
+//! ```
+//!
+//! ## Multiple limits
+//!
+//! [`GcrSet`] enforces several rules at once, such as a per-second and a per-day limit. A request
+//! only consumes units if every rule allows it, and is otherwise denied for the longest wait.
+//!
+#![cfg_attr(feature = "std", doc = "```rust")]
+#![cfg_attr(not(feature = "std"), doc = "```rust,ignore")]
+//! use gcr::GcrSet;
+//! use std::time::Duration;
+//!
+//! let mut rate = GcrSet::new()
+//!     .with_rule(10, Duration::from_secs(1), None)
+//!     .unwrap()
+//!     .with_rule(10_000, Duration::from_secs(86_400), None)
+//!     .unwrap();
+//!
+//! rate.request(10).unwrap();
+//! assert_eq!(rate.capacity(), 0);
+//! ```
+//!
+//! ## Hierarchical limits
+//!
+//! [`GcrTree`] nests limiters, such as per-user under per-tenant under global. A request must be
+//! allowed by a limiter and all of its ancestors, and reports the depth of the one that denied it.
+//! [`GcrTree::adjust`] can scale every limiter below the one being adjusted by the same factor.
+//!
+#![cfg_attr(feature = "std", doc = "```rust")]
+#![cfg_attr(not(feature = "std"), doc = "```rust,ignore")]
+//! use gcr::{GcrRequestError, GcrTree, GcrTreeError};
+//! use std::time::Duration;
+//!
+//! let mut rate = GcrTree::new(100, Duration::from_secs(1), None).unwrap();
+//! rate.insert(&[], "acme", 20, Duration::from_secs(1), None).unwrap();
+//! rate.insert(&["acme"], "alice", 10, Duration::from_secs(1), None).unwrap();
+//!
+//! rate.request(&["acme", "alice"], 10).unwrap();
+//! assert!(matches!(
+//!     rate.request(&["acme", "alice"], 10),
+//!     Err(GcrTreeError::Request { depth: 2, error: GcrRequestError::DeniedFor(_) })
+//! ));
+//!
+//! // Halve the tenant's rate, and with it the rate of each of its users
+//! rate.adjust(&["acme"], 10, Duration::from_secs(1), None, true).unwrap();
+//! ```
+//!
+//! ## Rate limit headers
+//!
+//! [`Gcr::rate_limit_info`] returns a [`RateLimitInfo`] with the limit, remaining units, and how
+//! long until the limiter resets or the next unit is available. It can be turned into the
+//! `RateLimit-Limit`, `RateLimit-Remaining`, `RateLimit-Reset`, and `Retry-After` headers from the
+//! IETF RateLimit header fields draft, with times rounded up to whole seconds.
+//!
+#![cfg_attr(feature = "std", doc = "```rust")]
+#![cfg_attr(not(feature = "std"), doc = "```rust,ignore")]
+//! use gcr::Gcr;
+//! use std::time::Duration;
+//!
+//! let mut rate = Gcr::new(10, Duration::from_secs(1), Some(30)).unwrap();
+//!
+//! rate.request(30).unwrap();
+//! let error = rate.request(10).unwrap_err();
+//! for (name, value) in rate.rate_limit_info().denied_headers(&error) {
+//!     println!("{name}: {value}"); // ratelimit-limit: 30, ..., retry-after: 1
+//! }
+//! ```
+//!
+//! ## Observability
+//!
+//! [`Gcr::with_observer`] attaches a [`GcrObserver`], which is called on every decision with the
+//! number of units, the outcome, and the capacity left. [`GcrCounter`] is a built-in observer that
+//! keeps totals of the units allowed and denied, and of how long callers were told to wait.
+//!
+#![cfg_attr(feature = "std", doc = "```rust")]
+#![cfg_attr(not(feature = "std"), doc = "```rust,ignore")]
+//! use gcr::{Gcr, GcrCounter};
+//! use std::{sync::Arc, time::Duration};
+//!
+//! let counter = Arc::new(GcrCounter::new());
+//! let mut rate = Gcr::new(10, Duration::from_secs(1), None)
+//!     .unwrap()
+//!     .with_observer(counter.clone());
+//!
+//! rate.request(10).unwrap();
+//! rate.request(5).unwrap_err();
+//! assert_eq!((counter.allowed(), counter.denied()), (10, 5));
+//! ```
+//!
+//! ## Shared state
+//!
+//! [`StoredGcr`] keeps its theoretical arrival time in a [`GcrStore`], such as a database shared
+//! by every replica of a service, so they all enforce one limit. A store only needs to support
+//! loading the theoretical arrival time and atomically replacing it if it has not changed.
+//! [`MemoryStore`] keeps it in memory, which is useful as a stand-in for a shared backend in tests.
+//! [`StoredGcr::new`] reads the time from the [`WallClock`] (time since the Unix epoch), so
+//! replicas in different processes agree on the times in the store.
+//!
+#![cfg_attr(feature = "std", doc = "```rust")]
+#![cfg_attr(not(feature = "std"), doc = "```rust,ignore")]
+//! use gcr::{MemoryStore, StoredGcr};
+//! use std::{sync::Arc, time::Duration};
+//!
+//! let store = Arc::new(MemoryStore::new());
+//! let replica_a = StoredGcr::new(10, Duration::from_secs(1), None, store.clone()).unwrap();
+//! let replica_b = StoredGcr::new(10, Duration::from_secs(1), None, store).unwrap();
+//!
+//! replica_a.request(10).unwrap();
+//! replica_b.request(1).unwrap_err(); // Both replicas share the same 10 units
+//! ```
+//!
+//! ## Middleware
+//!
+//! With the `tower` feature, [`GcrLayer`] requests one unit per request to the wrapped service
+//! and responds with a rejection (such as a 429) built from the [`GcrRequestError`] if it is
+//! denied. [`KeyedGcrLayer`] does the same per key, such as per user or IP address, and
+//! [`GcrBackpressureLayer`] keeps the service from being ready until a unit is available instead.
+//!
+//! ```rust,ignore
+//! let layer = GcrLayer::new(rate, |error| match error {
+//!     GcrRequestError::DeniedFor(duration) => too_many_requests(duration),
+//!     _ => service_unavailable(),
+//! });
+//! let layer = KeyedGcrLayer::new(keyed, |request: &Request| client_ip(request), reject);
+//! let layer = GcrBackpressureLayer::new(rate, tokio::time::sleep);
+//! ```
+//!
+//! ## Throttling streams
+//!
+//! [`ThrottledReader`] and [`ThrottledWriter`] wrap any [`Read`](std::io::Read) or
+//! [`Write`](std::io::Write) and request one unit per byte, blocking until the bytes are
+//! available. Transfers are split into chunks of at most the maximum burst, and several streams
+//! can share one limit.
+//!
+#![cfg_attr(feature = "std", doc = "```rust")]
+#![cfg_attr(not(feature = "std"), doc = "```rust,ignore")]
+//! use gcr::{Gcr, MockClock, ThrottledReader, ThrottledWriter};
+//! use std::{io, time::Duration};
+//!
+//! let clock = MockClock::new();
+//! let rate = Gcr::with_clock(1000, Duration::from_secs(1), None, clock.clone()).unwrap();
+//!
+//! let mut reader = ThrottledReader::new(&[0; 3000][..], rate); // 1000 bytes per second
+//! let mut writer = ThrottledWriter::shared(Vec::new(), reader.limiter().clone());
+//!
+//! io::copy(&mut reader, &mut writer).unwrap(); // Both streams count towards the same limit
+//! assert_eq!(clock.elapsed(), Duration::from_secs(5));
+//! ```
+//!
+//! With the `tokio` feature, [`ThrottledAsyncRead`] and [`ThrottledAsyncWrite`] do the same for
+//! `tokio`'s `AsyncRead` and `AsyncWrite`. Each poll transfers at most as many bytes as are
+//! available, and waits for the next one with any async runtime's sleep function.
+//!
+//! ```rust,ignore
+//! let mut reader = ThrottledAsyncRead::new(socket, rate, tokio::time::sleep);
+//! let mut writer = ThrottledAsyncWrite::shared(upstream, reader.limiter().clone(), tokio::time::sleep);
+//! tokio::io::copy(&mut reader, &mut writer).await?;
+//! ```
+//!
+//! ## `no_std`
+//!
+//! Disabling the default `std` feature makes the crate `no_std`. Time is then measured in
+//! [`Timestamp`]s (nanoseconds since an arbitrary origin) read from any `Fn() -> Timestamp`,
+//! such as a hardware timer. [`SystemClock`], [`WallClock`], [`MockClock`], [`KeyedGcr`], [`GcrSet`], [`GcrTree`], [`GcrSnapshot`],
+//! [`MemoryStore`], [`SharedPermit`], [`ThrottledReader`], [`ThrottledWriter`], and passing [`Instant`](std::time::Instant)s to the `*_at` methods all require `std`.
+//!
+//! ```rust
+//! use gcr::{Gcr, Timestamp};
+//! use std::time::Duration;
+//!
+//! fn read_timer() -> Timestamp {
+//!     Timestamp::from_nanos(0) // Read the current time from the hardware timer
+//! }
+//!
+//! let mut rate = Gcr::with_clock(10, Duration::from_secs(1), Some(30), read_timer).unwrap();
+//! rate.request(30).unwrap();
+//! ```
 
-use core::fmt;
-use std::{
-    cmp::{self, max},
-    fmt::Display,
-    time::{Duration, Instant},
+#![cfg_attr(not(feature = "std"), no_std)]
+
+use core::{
+    cmp,
+    fmt::{self, Display},
+    time::Duration,
 };
 
-#[cfg(test)]
+#[cfg(feature = "tokio")]
+mod async_io;
+#[cfg(target_has_atomic = "64")]
+mod atomic;
+mod builder;
+mod clock;
+mod info;
+#[cfg(feature = "std")]
+mod io;
+#[cfg(feature = "std")]
+mod keyed;
+#[cfg(feature = "tower")]
+mod middleware;
+mod observer;
+mod params;
+mod permit;
+mod reservation;
+#[cfg(feature = "std")]
+mod set;
+#[cfg(feature = "std")]
+mod snapshot;
+mod store;
+#[cfg(feature = "std")]
+mod tree;
+mod wait;
+#[cfg(feature = "tokio")]
+pub use async_io::{ThrottledAsyncRead, ThrottledAsyncWrite};
+#[cfg(target_has_atomic = "64")]
+pub use atomic::AtomicGcr;
+pub use builder::GcrBuilder;
+pub use clock::{Clock, DefaultClock, Timestamp};
+#[cfg(feature = "std")]
+pub use clock::{MockClock, SystemClock, WallClock};
+pub use info::{
+    RateLimitInfo, RATE_LIMIT_LIMIT, RATE_LIMIT_REMAINING, RATE_LIMIT_RESET, RETRY_AFTER,
+};
+#[cfg(feature = "std")]
+pub use io::{ThrottledReader, ThrottledWriter};
+#[cfg(feature = "std")]
+pub use keyed::KeyedGcr;
+#[cfg(feature = "tower")]
+pub use middleware::{
+    GcrBackpressureLayer, GcrBackpressureService, GcrLayer, GcrResponseFuture, GcrService,
+    KeyedGcrLayer, KeyedGcrService,
+};
+#[cfg(target_has_atomic = "64")]
+pub use observer::GcrCounter;
+pub use observer::GcrObserver;
+use params::Params;
+pub use permit::Permit;
+#[cfg(feature = "std")]
+pub use permit::SharedPermit;
+pub use reservation::Reservation;
+#[cfg(feature = "std")]
+pub use set::GcrSet;
+#[cfg(feature = "std")]
+pub use snapshot::GcrSnapshot;
+#[cfg(feature = "std")]
+pub use store::MemoryStore;
+pub use store::{GcrStore, StoredGcr};
+#[cfg(feature = "std")]
+pub use tree::GcrTree;
+
+#[cfg(all(test, feature = "std"))]
 mod test;
+#[cfg(test)]
+mod test_no_std;
+
+/// Lock a shared limiter or store. Neither is ever left in an inconsistent state, so a poisoned
+/// lock is still safe to use
+#[cfg(feature = "std")]
+pub(crate) fn lock<T>(mutex: &std::sync::Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    mutex
+        .lock()
+        .unwrap_or_else(std::sync::PoisonError::into_inner)
+}
+
+/// A point in time tracked by a [`Gcr`], used to report which one overflowed
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum InstantField {
+    /// The theoretical arrival time of the next unit
+    TheoreticalArrivalTime,
+}
+
+/// Display implementation for [`InstantField`]
+impl Display for InstantField {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::TheoreticalArrivalTime => write!(f, "theoretical arrival time"),
+        }
+    }
+}
 
 /// Errors encountered when creating a new [`Gcr`] instance
 #[derive(Debug, Eq, PartialEq, Clone)]
 pub enum GcrCreationError {
-    ParametersOutOfRange(String),
+    /// Neither a rate nor an emission interval was supplied
+    MissingRate,
+    /// A rate was supplied without a period
+    MissingPeriod,
+    /// Both a rate and an emission interval were supplied
+    ConflictingRate,
+    /// The supplied rate was zero
+    ZeroRate,
+    /// The emission interval or period was zero
+    ZeroEmissionInterval,
+    /// An emission interval was supplied without a burst
+    MissingBurst,
+    /// Both a burst and a burst duration were supplied
+    ConflictingBurst,
+    /// The burst duration was shorter than a single emission interval
+    BurstDurationTooShort,
+    /// The burst duration allowed more units than fit in the max burst
+    BurstDurationTooLong,
+    /// The initial capacity was larger than the maximum burst
+    InitialCapacityTooLarge {
+        initial_capacity: u64,
+        max_burst: u64,
+    },
+    /// The parameters of a [`GcrSnapshot`] were invalid
+    InconsistentSnapshot,
+    /// The delay tolerance (period / rate * max_burst) was too large to represent
+    DelayToleranceOverflow,
+    /// A point in time was too far from the current time to represent
+    InstantOverflow { field: InstantField },
 }
 
 /// Display implementation for [`GcrCreationError`]
 impl Display for GcrCreationError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Self::ParametersOutOfRange(msg) => write!(f, "Parameters out of range: {}", msg),
+            Self::MissingRate => write!(f, "Neither a rate nor an emission interval was supplied"),
+            Self::MissingPeriod => write!(f, "A rate was supplied without a period"),
+            Self::ConflictingRate => {
+                write!(f, "Both a rate and an emission interval were supplied")
+            }
+            Self::ZeroRate => write!(f, "Supplied rate was zero"),
+            Self::ZeroEmissionInterval => write!(f, "Emission interval was zero"),
+            Self::MissingBurst => write!(f, "An emission interval was supplied without a burst"),
+            Self::ConflictingBurst => write!(f, "Both a burst and a burst duration were supplied"),
+            Self::BurstDurationTooShort => {
+                write!(f, "Burst duration was shorter than the emission interval")
+            }
+            Self::BurstDurationTooLong => {
+                write!(f, "Burst duration / emission interval was too large")
+            }
+            Self::InitialCapacityTooLarge {
+                initial_capacity,
+                max_burst,
+            } => write!(
+                f,
+                "Initial capacity {} was larger than the max burst {}",
+                initial_capacity, max_burst
+            ),
+            Self::InconsistentSnapshot => {
+                write!(f, "Snapshot parameters were invalid")
+            }
+            Self::DelayToleranceOverflow => write!(f, "(Period / rate * max_burst) was too large"),
+            Self::InstantOverflow { field } => write!(f, "The {} was out of range", field),
         }
     }
 }
 
-/// Errors encountered when requesting units from a [`Gcr`] instance
+#[cfg(feature = "std")]
+impl std::error::Error for GcrCreationError {}
+
+/// Errors encountered when requesting units from a [`Gcr`] instance.
+///
+/// None of these allocate, so a denied request never touches the heap.
 #[derive(Debug, Eq, PartialEq, Clone)]
 pub enum GcrRequestError {
+    /// The request was denied. Includes the duration until it would be allowed
     DeniedFor(Duration),
+    /// The request was larger than the max burst, so it can never be allowed
     RequestTooLarge,
-    ParametersOutOfRange(String),
+    /// A point in time was too far from the current time to represent
+    InstantOverflow { field: InstantField },
 }
 
 /// Display implementation for [`GcrRequestError`]
@@ -73,100 +625,194 @@ impl Display for GcrRequestError {
         match self {
             Self::DeniedFor(duration) => write!(f, "Request denied for {:?}", duration),
             Self::RequestTooLarge => write!(f, "Request was too large to ever be allowed"),
-            Self::ParametersOutOfRange(msg) => write!(f, "Parameters out of range: {}", msg),
+            Self::InstantOverflow { field } => write!(f, "The {} was out of range", field),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for GcrRequestError {}
+
+/// Errors encountered when using a [`GcrTree`]
+#[cfg(feature = "std")]
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub enum GcrTreeError {
+    /// There was no limiter at the given path
+    UnknownPath,
+    /// There was already a limiter at the given path
+    PathExists,
+    /// A limiter failed the request. Includes its depth, zero being the root
+    Request {
+        depth: usize,
+        error: GcrRequestError,
+    },
+    /// The parameters for a limiter were invalid
+    Creation(GcrCreationError),
+}
+
+/// Display implementation for [`GcrTreeError`]
+#[cfg(feature = "std")]
+impl Display for GcrTreeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnknownPath => write!(f, "There was no limiter at the given path"),
+            Self::PathExists => write!(f, "There was already a limiter at the given path"),
+            Self::Request { depth, error } => write!(f, "{} at depth {}", error, depth),
+            Self::Creation(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for GcrTreeError {}
+
+/// Errors encountered when requesting units from a [`StoredGcr`] instance
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub enum GcrStoreError<E> {
+    /// The request failed. See [`GcrRequestError`]
+    Request(GcrRequestError),
+    /// The [`GcrStore`] could not be read or written
+    Store(E),
+}
+
+/// Display implementation for [`GcrStoreError`]
+impl<E: Display> Display for GcrStoreError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Request(error) => write!(f, "{}", error),
+            Self::Store(error) => write!(f, "The store failed: {}", error),
         }
     }
 }
 
+#[cfg(feature = "std")]
+impl<E: fmt::Debug + Display> std::error::Error for GcrStoreError<E> {}
+
 /// A generic cell rate (GCR) algorithm instance
 #[derive(Clone, Debug, PartialEq, Eq)]
-pub struct Gcr {
-    /// The "refill" rate
-    emission_interval: Duration,
-    delay_tolerance: Duration,
-    /// The theoretical arrival time of the next unit
-    theoretical_arrival_time: Instant,
-    /// The time at which the next unit is allowed
-    allow_at: Instant,
-    /// The maximum number of units to allow in a single request
-    max_burst: u32,
+pub struct Gcr<C: Clock = DefaultClock, O: GcrObserver = ()> {
+    /// The rate, period, and max burst
+    params: Params,
+    /// The theoretical arrival time of the next unit, in ticks of `1 / rate` nanoseconds
+    theoretical_arrival_time: u128,
+    /// The longest a [`Reservation`] is allowed to wait for its units
+    max_wait: Option<Duration>,
+    /// The most units [`Gcr::charge`] may consume beyond the capacity
+    max_debt: Option<u64>,
+    /// The source of the current time
+    clock: C,
+    /// Receives every decision
+    observer: O,
 }
 
+#[cfg(feature = "std")]
 impl Gcr {
-    /// Create a new [`Gcr`] instance.
+    /// Create a new [`Gcr`] instance that uses the [`SystemClock`].
     ///
     /// * `rate` - The number of units to "refill" per `period`
     /// * `period` - The amount of time between each "refill"
     /// * `max_burst` - The maximum number of units to allow in a single request. If
-    ///     not specified, this will be set to the rate.
+    ///   not specified, this will be set to the rate.
     ///
     /// Returns a new [`Gcr`] instance on success.
     ///
     /// # Errors
-    /// - [`GcrCreationError::ParametersOutOfRange`] - if the parameters are out of range
+    /// - [`GcrCreationError::ZeroRate`] - if the rate was zero
+    /// - [`GcrCreationError::ZeroEmissionInterval`] - if the period was zero
+    /// - [`GcrCreationError::DelayToleranceOverflow`] - if the parameters are out of range
     pub fn new(
-        rate: u32,
+        rate: u64,
         period: Duration,
-        max_burst: Option<u32>,
+        max_burst: Option<u64>,
     ) -> Result<Self, GcrCreationError> {
-        // The emission interval is the "refill" rate
-        let emission_interval =
-            period
-                .checked_div(rate)
-                .ok_or(GcrCreationError::ParametersOutOfRange(
-                    "Supplied rate was zero".to_string(),
-                ))?;
-
-        // If not set, the max burst is the rate
-        let max_burst = max_burst.unwrap_or(rate);
-
-        // The delay tolerance is the time between the theoretical arrival time and the
-        // allow at time
-        let delay_tolerance = emission_interval.checked_mul(max_burst).ok_or(
-            GcrCreationError::ParametersOutOfRange("Period / rate was too large".to_string()),
-        )?;
+        Self::with_clock(rate, period, max_burst, SystemClock)
+    }
+}
 
-        // This is set to the current time so we can instantly have our full burst
-        let theoretical_arrival_time = Instant::now();
+impl<C: Clock> Gcr<C> {
+    /// Create a new [`Gcr`] instance that reads the current time from `clock`.
+    ///
+    /// Accepts the same parameters as [`Gcr::new`]. Without the `std` feature, this is the only
+    /// way to create a [`Gcr`], since there is no [`SystemClock`].
+    ///
+    /// # Errors
+    /// - [`GcrCreationError::ZeroRate`] - if the rate was zero
+    /// - [`GcrCreationError::ZeroEmissionInterval`] - if the period was zero
+    /// - [`GcrCreationError::DelayToleranceOverflow`] - if the parameters are out of range
+    pub fn with_clock(
+        rate: u64,
+        period: Duration,
+        max_burst: Option<u64>,
+        clock: C,
+    ) -> Result<Self, GcrCreationError> {
+        let params = Params::new(rate, period, max_burst)?;
 
-        // The allow_at time is the theoretical arrival time minus the delay tolerance
-        let allow_at = theoretical_arrival_time
-            .checked_sub(delay_tolerance)
-            .ok_or(GcrCreationError::ParametersOutOfRange(
-                "Period / rate was too large".to_string(),
-            ))?;
+        // This is set to the current time so we can instantly have our full burst
+        let theoretical_arrival_time = params.ticks(clock.now());
 
         Ok(Self {
-            max_burst,
-            emission_interval,
-            delay_tolerance,
+            params,
             theoretical_arrival_time,
-            allow_at,
+            max_wait: None,
+            max_debt: None,
+            clock,
+            observer: (),
         })
     }
+}
+
+impl<C: Clock, O: GcrObserver> Gcr<C, O> {
+    /// Attach a [`GcrObserver`] that is called on every decision, replacing any existing one
+    pub fn with_observer<P: GcrObserver>(self, observer: P) -> Gcr<C, P> {
+        Gcr {
+            params: self.params,
+            theoretical_arrival_time: self.theoretical_arrival_time,
+            max_wait: self.max_wait,
+            max_debt: self.max_debt,
+            clock: self.clock,
+            observer,
+        }
+    }
+
+    /// Get a reference to the [`GcrObserver`] used by this instance
+    pub fn observer(&self) -> &O {
+        &self.observer
+    }
+
+    /// Get a reference to the [`Clock`] used by this instance
+    pub fn clock(&self) -> &C {
+        &self.clock
+    }
 
     /// Get the capacity of the rate limiter at a given time.
     ///
+    /// `now` may be a [`Timestamp`], or an [`Instant`](std::time::Instant) with the `std` feature.
+    ///
     /// Note: this function calculates the capacity on the fly
-    fn capacity_at(&self, now: Instant) -> u32 {
-        // Get the duration since the allow at time
-        let Some(time_since) = now.checked_duration_since(self.allow_at) else {
-            return 0;
-        };
-
-        // Return the min of the number of emission intervals that have passed (units allowed)
-        // and the max burst
-        cmp::min(
-            time_since.div_duration_f64(self.emission_interval) as u32,
-            self.max_burst,
-        )
+    pub fn capacity_at(&self, now: impl Into<Timestamp>) -> u64 {
+        self.params
+            .capacity(self.theoretical_arrival_time, now.into())
     }
 
     /// Get the current capacity of the rate limiter
     ///
     /// Note: this function calculates the capacity on the fly
-    pub fn capacity(&self) -> u32 {
-        self.capacity_at(Instant::now())
+    pub fn capacity(&self) -> u64 {
+        self.capacity_at(self.clock.now())
+    }
+
+    /// Whether the rate limiter has fully refilled by `now`.
+    ///
+    /// An idle instance behaves exactly like a newly created one with the same parameters,
+    /// so it can be dropped and recreated without changing any limiting decisions.
+    pub fn is_idle_at(&self, now: impl Into<Timestamp>) -> bool {
+        self.params
+            .is_idle(self.theoretical_arrival_time, now.into())
+    }
+
+    /// Whether the rate limiter has fully refilled
+    pub fn is_idle(&self) -> bool {
+        self.is_idle_at(self.clock.now())
     }
 
     /// Request `n` units from the rate limiter.
@@ -176,103 +822,207 @@ impl Gcr {
     /// # Errors
     /// - [`GcrRequestError::DeniedFor`] - if the request was denied. Includes the duration until the next successful request of the same size can be made.
     /// - [`GcrRequestError::RequestTooLarge`] - if the request was too large to ever be allowed. This happens if the request size is greater than the maximum burst (or the `rate` if it was not set)
-    /// - [`GcrRequestError::ParametersOutOfRange`] - if the [`Gcr`] parameters are out of range
-    pub fn request(&mut self, n: u32) -> Result<(), GcrRequestError> {
-        // If the request is greater than the maximum request size, deny it with an error
-        if n > self.max_burst {
-            return Err(GcrRequestError::RequestTooLarge);
+    /// - [`GcrRequestError::InstantOverflow`] - if the theoretical arrival time would be out of range
+    pub fn request(&mut self, n: u64) -> Result<(), GcrRequestError> {
+        self.request_at(n, self.clock.now())
+    }
+
+    /// Request `n` units from the rate limiter as if the request was made at `now`.
+    ///
+    /// This behaves exactly like [`Gcr::request`], but uses the supplied time instead of reading
+    /// it from the [`Clock`]. This allows logged traffic to be replayed with its original timestamps.
+    ///
+    /// # Errors
+    /// - See [`Gcr::request`]
+    pub fn request_at(&mut self, n: u64, now: impl Into<Timestamp>) -> Result<(), GcrRequestError> {
+        self.reserve_within(n, now.into(), Some(Duration::ZERO))
+            .map(|_| ())
+    }
+
+    /// Check whether a request for `n` units would be allowed, without consuming anything.
+    ///
+    /// This runs exactly the same checks as [`Gcr::request`], so if it succeeds, a request for
+    /// `n` units made at the same time would too. It is not reported to the [`GcrObserver`].
+    ///
+    /// # Errors
+    /// - See [`Gcr::request`]
+    pub fn check(&self, n: u64) -> Result<(), GcrRequestError> {
+        self.check_at(n, self.clock.now())
+    }
+
+    /// Check whether a request for `n` units made at `now` would be allowed, without consuming
+    /// anything.
+    ///
+    /// # Errors
+    /// - See [`Gcr::request`]
+    pub fn check_at(&self, n: u64, now: impl Into<Timestamp>) -> Result<(), GcrRequestError> {
+        self.params
+            .request(self.theoretical_arrival_time, now.into(), n)
+            .map(|_| ())
+    }
+
+    /// Get how long until a request for `n` units would be allowed, without consuming anything.
+    ///
+    /// Returns zero if it would be allowed now, and `None` if it can never be allowed (such as
+    /// if `n` is larger than the max burst).
+    pub fn time_until(&self, n: u64) -> Option<Duration> {
+        self.time_until_at(n, self.clock.now())
+    }
+
+    /// Get how long after `now` a request for `n` units would be allowed, without consuming
+    /// anything.
+    ///
+    /// Returns zero if it would be allowed at `now`, and `None` if it can never be allowed.
+    pub fn time_until_at(&self, n: u64, now: impl Into<Timestamp>) -> Option<Duration> {
+        match self.check_at(n, now) {
+            Ok(()) => Some(Duration::ZERO),
+            Err(GcrRequestError::DeniedFor(duration)) => Some(duration),
+            Err(_) => None,
         }
+    }
 
-        // This is the canonical request time
-        let now = Instant::now();
-
-        // Calculate how long it would take to allow the request
-        let required_duration =
-            self.emission_interval
-                .checked_mul(n)
-                .ok_or(GcrRequestError::ParametersOutOfRange(
-                    "Period / rate was too large".to_string(),
-                ))?;
-
-        // If the request exceeds capacity, deny it
-        if n > self.capacity_at(now) {
-            // If we are not past the virtual theoretical arrival time, disallow the request
-
-            // Calculate the time at which all units would have been allowed
-            let allow_time = self.allow_at.checked_add(required_duration).ok_or(
-                GcrRequestError::ParametersOutOfRange("Period / rate was too large".to_string()),
-            )?;
-
-            // See how far it is from the current time
-            let denied_for = allow_time.checked_duration_since(now);
-            if let Some(denied_for) = denied_for {
-                return Err(GcrRequestError::DeniedFor(denied_for));
+    /// Request up to `n` units from the rate limiter, granting as many as are currently available.
+    ///
+    /// Returns the number of units granted, which may be zero. The granted units are consumed
+    /// exactly as if they had been passed to [`Gcr::request`].
+    pub fn request_up_to(&mut self, n: u64) -> u64 {
+        self.request_up_to_at(n, self.clock.now())
+    }
+
+    /// Request up to `n` units from the rate limiter as if the request was made at `now`.
+    ///
+    /// Returns the number of units granted, which may be zero.
+    pub fn request_up_to_at(&mut self, n: u64, now: impl Into<Timestamp>) -> u64 {
+        let now = now.into();
+
+        // Grant whatever is available, which is never more than the max burst
+        let granted = cmp::min(n, self.capacity_at(now));
+        if granted == 0 {
+            return 0;
+        }
+
+        // If the parameters are out of range, nothing can be granted
+        match self.request_at(granted, now) {
+            Ok(()) => granted,
+            Err(_) => 0,
+        }
+    }
+
+    /// Reserve `n` units as if the request was made at `now`, even if they are not available yet.
+    ///
+    /// On success, the units are consumed and the time to wait until they are available is returned.
+    /// If that wait would be longer than `max_wait`, nothing is consumed and the request is denied
+    /// for the difference. Every request ends up here, so this is where the decision is observed.
+    fn reserve_within(
+        &mut self,
+        n: u64,
+        now: Timestamp,
+        max_wait: Option<Duration>,
+    ) -> Result<Duration, GcrRequestError> {
+        match self
+            .params
+            .reserve(self.theoretical_arrival_time, now, n, max_wait)
+        {
+            Ok((theoretical_arrival_time, wait)) => {
+                self.theoretical_arrival_time = theoretical_arrival_time;
+                if n > 0 {
+                    self.observer.on_allowed(n, wait, self.capacity_at(now));
+                }
+                Ok(wait)
+            }
+            Err(error) => {
+                self.observer.on_denied(n, &error, self.capacity_at(now));
+                Err(error)
             }
         }
+    }
 
-        // We are past the virtual theoretical arrival time, so allow the request
+    /// Return up to `n` previously granted units to the rate limiter, such as when the work they
+    /// were requested for failed.
+    ///
+    /// The theoretical arrival time is never moved before the current time, so this can never
+    /// result in more than the maximum burst being available. Units that would exceed it are
+    /// dropped.
+    pub fn release(&mut self, n: u64) {
+        self.release_at(n, self.clock.now());
+    }
 
-        // Update the theoretical arrival time to account for the new units consumed
-        self.theoretical_arrival_time = max(self.theoretical_arrival_time, now)
-            .checked_add(required_duration)
-            .ok_or(GcrRequestError::ParametersOutOfRange(
-                "Period / rate was too large".to_string(),
-            ))?;
+    /// Return up to `n` previously granted units to the rate limiter as if they were returned at `now`.
+    pub fn release_at(&mut self, n: u64, now: impl Into<Timestamp>) {
+        self.theoretical_arrival_time =
+            self.params
+                .release(self.theoretical_arrival_time, now.into(), n);
+    }
 
-        // Update the `allow_at` time to account for the new units consumed
-        self.allow_at = self
-            .theoretical_arrival_time
-            .checked_sub(self.delay_tolerance)
-            .ok_or(GcrRequestError::ParametersOutOfRange(
-                "(Period / rate * max_burst) was too large".to_string(),
-            ))?;
+    /// Set the most units [`Gcr::charge`] may consume beyond the capacity.
+    ///
+    /// By default, any number of units may be charged.
+    pub fn with_max_debt(mut self, max_debt: u64) -> Self {
+        self.max_debt = Some(max_debt);
+        self
+    }
 
-        Ok(())
+    /// Consume `n` units from the rate limiter unconditionally, such as for work whose cost is
+    /// only known after it was done.
+    ///
+    /// Units beyond the current capacity are debt: requests are denied until it has been paid off
+    /// by the time that passes. The debt is never allowed to exceed the maximum set with
+    /// [`Gcr::with_max_debt`], so fewer units may be charged. Returns the number of units charged.
+    pub fn charge(&mut self, n: u64) -> u64 {
+        self.charge_at(n, self.clock.now())
+    }
+
+    /// Consume `n` units from the rate limiter unconditionally as if they were charged at `now`.
+    pub fn charge_at(&mut self, n: u64, now: impl Into<Timestamp>) -> u64 {
+        let now = now.into();
+        let (theoretical_arrival_time, charged) =
+            self.params
+                .charge(self.theoretical_arrival_time, now, n, self.max_debt);
+        self.theoretical_arrival_time = theoretical_arrival_time;
+        if charged > 0 {
+            self.observer
+                .on_allowed(charged, Duration::ZERO, self.capacity_at(now));
+        }
+        charged
     }
 
     /// Adjust the parameters of the rate limiter while preserving the current capacity.
     ///
     /// # Errors
-    /// - [`GcrCreationError::ParametersOutOfRange`] - if the parameters are out of range
+    /// - [`GcrCreationError::ZeroRate`] - if the rate was zero
+    /// - [`GcrCreationError::ZeroEmissionInterval`] - if the period was zero
+    /// - [`GcrCreationError::DelayToleranceOverflow`] or [`GcrCreationError::InstantOverflow`] - if the parameters are out of range
     pub fn adjust(
         &mut self,
-        rate: u32,
+        rate: u64,
         period: Duration,
-        max_burst: Option<u32>,
+        max_burst: Option<u64>,
     ) -> Result<(), GcrCreationError> {
-        // Create a new `Gcr` with the new rate, period, and max burst
-        let mut new_rate = Gcr::new(rate, period, max_burst)?;
-
-        // This is the canonical request time
-        let now = Instant::now();
-
-        // Get the duration since the allow at time
-        if let Some(time_since) = now.checked_duration_since(self.allow_at) {
-            // Update the allow at time to account for the new rate
-            new_rate.allow_at = now
-                .checked_sub(
-                    new_rate
-                        .emission_interval
-                        .checked_mul(time_since.div_duration_f64(self.emission_interval) as u32)
-                        .ok_or(GcrCreationError::ParametersOutOfRange(
-                            "Period / rate was too large".to_string(),
-                        ))?,
-                )
-                .ok_or(GcrCreationError::ParametersOutOfRange(
-                    "Period / rate was too large".to_string(),
-                ))?;
-
-            // Update the theoretical arrival time to account for the new rate
-            new_rate.theoretical_arrival_time = new_rate
-                .allow_at
-                .checked_add(new_rate.delay_tolerance)
-                .ok_or(GcrCreationError::ParametersOutOfRange(
-                    "Delay tolerance was too large".to_string(),
-                ))?;
-        }
+        self.adjust_at(rate, period, max_burst, self.clock.now())
+    }
+
+    /// Adjust the parameters of the rate limiter as if the adjustment was made at `now`,
+    /// preserving the capacity at that time.
+    ///
+    /// # Errors
+    /// - [`GcrCreationError::ZeroRate`] - if the rate was zero
+    /// - [`GcrCreationError::ZeroEmissionInterval`] - if the period was zero
+    /// - [`GcrCreationError::DelayToleranceOverflow`] or [`GcrCreationError::InstantOverflow`] - if the parameters are out of range
+    pub fn adjust_at(
+        &mut self,
+        rate: u64,
+        period: Duration,
+        max_burst: Option<u64>,
+        now: impl Into<Timestamp>,
+    ) -> Result<(), GcrCreationError> {
+        // Calculate the new emission interval, delay tolerance, and max burst
+        let params = Params::new(rate, period, max_burst)?;
 
-        // Replace ourselves with the new rate
-        *self = new_rate;
+        // Carry the current capacity (or reserved units) over to the new parameters
+        self.theoretical_arrival_time =
+            self.params
+                .rebase(self.theoretical_arrival_time, now.into(), &params)?;
+        self.params = params;
 
         Ok(())
     }
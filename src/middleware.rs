@@ -0,0 +1,437 @@
+//! [`tower`](https://docs.rs/tower) middleware that rate limits every request to a service.
+
+use std::{
+    fmt,
+    future::Future,
+    hash::Hash,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{ready, Context, Poll},
+    time::Duration,
+};
+
+use pin_project_lite::pin_project;
+use tower_layer::Layer;
+use tower_service::Service;
+
+use crate::{lock, Clock, Gcr, GcrRequestError, KeyedGcr, SystemClock};
+
+/// A [`Layer`] that requests one unit from a shared [`Gcr`] for every request, and responds with
+/// `on_denied` instead of calling the inner service if it is not allowed.
+///
+/// ```rust
+/// use gcr::{Gcr, GcrLayer, GcrRequestError};
+/// use std::time::Duration;
+///
+/// let rate = Gcr::new(10, Duration::from_secs(1), None).unwrap();
+/// let layer = GcrLayer::new(rate, |error| match error {
+///     GcrRequestError::DeniedFor(duration) => format!("429, retry after {duration:?}"),
+///     _ => String::from("503"),
+/// });
+/// ```
+#[derive(Clone)]
+pub struct GcrLayer<F, C: Clock = SystemClock> {
+    /// The limiter shared by every service this layer wraps
+    limiter: Arc<Mutex<Gcr<C>>>,
+    /// Builds the response for a request that was not allowed
+    on_denied: F,
+}
+
+impl<F, C: Clock> GcrLayer<F, C> {
+    /// Create a new [`GcrLayer`] that limits requests with `gcr` and responds to requests that
+    /// are not allowed with `on_denied`
+    pub fn new<R>(gcr: Gcr<C>, on_denied: F) -> Self
+    where
+        F: Fn(GcrRequestError) -> R,
+    {
+        Self {
+            limiter: Arc::new(Mutex::new(gcr)),
+            on_denied,
+        }
+    }
+
+    /// Get the shared [`Gcr`], such as to [adjust](Gcr::adjust) it while serving requests
+    pub fn limiter(&self) -> &Arc<Mutex<Gcr<C>>> {
+        &self.limiter
+    }
+}
+
+impl<S, F: Clone, C: Clock> Layer<S> for GcrLayer<F, C> {
+    type Service = GcrService<S, F, C>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        GcrService {
+            inner,
+            limiter: self.limiter.clone(),
+            on_denied: self.on_denied.clone(),
+        }
+    }
+}
+
+impl<F, C: Clock + fmt::Debug> fmt::Debug for GcrLayer<F, C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("GcrLayer")
+            .field("limiter", &self.limiter)
+            .finish_non_exhaustive()
+    }
+}
+
+/// The [`Service`] created by a [`GcrLayer`]
+#[derive(Clone)]
+pub struct GcrService<S, F, C: Clock = SystemClock> {
+    inner: S,
+    limiter: Arc<Mutex<Gcr<C>>>,
+    on_denied: F,
+}
+
+impl<S, F, C, Request> Service<Request> for GcrService<S, F, C>
+where
+    S: Service<Request>,
+    F: Fn(GcrRequestError) -> S::Response,
+    C: Clock,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = GcrResponseFuture<S::Future, S::Response>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request) -> Self::Future {
+        // Release the lock before calling the inner service
+        let result = lock(&self.limiter).request(1);
+        match result {
+            Ok(()) => GcrResponseFuture::inner(self.inner.call(request)),
+            Err(error) => GcrResponseFuture::rejected((self.on_denied)(error)),
+        }
+    }
+}
+
+impl<S: fmt::Debug, F, C: Clock + fmt::Debug> fmt::Debug for GcrService<S, F, C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("GcrService")
+            .field("inner", &self.inner)
+            .field("limiter", &self.limiter)
+            .finish_non_exhaustive()
+    }
+}
+
+/// A [`Layer`] that requests one unit from a shared [`Gcr`] for every request, and applies
+/// backpressure instead of rejecting requests that are not allowed.
+///
+/// The wrapped service is not ready until a unit is available. Whenever a request is denied, it
+/// waits for the [`GcrRequestError::DeniedFor`] duration by awaiting `sleep`, which lets any
+/// async runtime be used:
+///
+/// ```rust,ignore
+/// let layer = GcrBackpressureLayer::new(rate, tokio::time::sleep);
+/// ```
+///
+/// Any other [`GcrRequestError`] is returned from [`Service::poll_ready`], so the inner service's
+/// error type must implement `From<GcrRequestError>` (such as `Box<dyn Error + Send + Sync>`).
+#[derive(Clone)]
+pub struct GcrBackpressureLayer<Z, C: Clock = SystemClock> {
+    /// The limiter shared by every service this layer wraps
+    limiter: Arc<Mutex<Gcr<C>>>,
+    /// Returns a future that completes after the given duration
+    sleep: Z,
+}
+
+impl<Z, C: Clock> GcrBackpressureLayer<Z, C> {
+    /// Create a new [`GcrBackpressureLayer`] that limits requests with `gcr` and waits for units
+    /// to become available with `sleep`
+    pub fn new<T>(gcr: Gcr<C>, sleep: Z) -> Self
+    where
+        Z: Fn(Duration) -> T,
+        T: Future<Output = ()>,
+    {
+        Self {
+            limiter: Arc::new(Mutex::new(gcr)),
+            sleep,
+        }
+    }
+
+    /// Get the shared [`Gcr`], such as to [adjust](Gcr::adjust) it while serving requests
+    pub fn limiter(&self) -> &Arc<Mutex<Gcr<C>>> {
+        &self.limiter
+    }
+}
+
+impl<S, Z, T, C> Layer<S> for GcrBackpressureLayer<Z, C>
+where
+    Z: Fn(Duration) -> T + Clone,
+    C: Clock,
+{
+    type Service = GcrBackpressureService<S, Z, T, C>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        GcrBackpressureService {
+            inner,
+            limiter: self.limiter.clone(),
+            sleep: self.sleep.clone(),
+            sleeping: None,
+            permitted: false,
+        }
+    }
+}
+
+impl<Z, C: Clock + fmt::Debug> fmt::Debug for GcrBackpressureLayer<Z, C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("GcrBackpressureLayer")
+            .field("limiter", &self.limiter)
+            .finish_non_exhaustive()
+    }
+}
+
+/// The [`Service`] created by a [`GcrBackpressureLayer`]
+pub struct GcrBackpressureService<S, Z, T, C: Clock = SystemClock> {
+    inner: S,
+    limiter: Arc<Mutex<Gcr<C>>>,
+    sleep: Z,
+    /// The wait after the last denied request, if it has not finished yet
+    sleeping: Option<Pin<Box<T>>>,
+    /// Whether a unit has been consumed for the next call
+    permitted: bool,
+}
+
+/// Clones start without a unit or a pending wait of their own
+impl<S: Clone, Z: Clone, T, C: Clock> Clone for GcrBackpressureService<S, Z, T, C> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            limiter: self.limiter.clone(),
+            sleep: self.sleep.clone(),
+            sleeping: None,
+            permitted: false,
+        }
+    }
+}
+
+impl<S, Z, T, C, Request> Service<Request> for GcrBackpressureService<S, Z, T, C>
+where
+    S: Service<Request>,
+    S::Error: From<GcrRequestError>,
+    Z: Fn(Duration) -> T,
+    T: Future<Output = ()>,
+    C: Clock,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = S::Future;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        while !self.permitted {
+            if let Some(sleeping) = &mut self.sleeping {
+                ready!(sleeping.as_mut().poll(cx));
+                self.sleeping = None;
+            }
+
+            // Another clone may have taken the unit while we were waiting, so try again
+            let result = lock(&self.limiter).request(1);
+            match result {
+                Ok(()) => self.permitted = true,
+                Err(GcrRequestError::DeniedFor(duration)) => {
+                    self.sleeping = Some(Box::pin((self.sleep)(duration)));
+                }
+                Err(error) => return Poll::Ready(Err(error.into())),
+            }
+        }
+
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request) -> Self::Future {
+        assert!(
+            self.permitted,
+            "GcrBackpressureService::call was called before poll_ready returned Ready"
+        );
+        self.permitted = false;
+        self.inner.call(request)
+    }
+}
+
+impl<S: fmt::Debug, Z, T, C: Clock + fmt::Debug> fmt::Debug for GcrBackpressureService<S, Z, T, C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("GcrBackpressureService")
+            .field("inner", &self.inner)
+            .field("limiter", &self.limiter)
+            .field("permitted", &self.permitted)
+            .finish_non_exhaustive()
+    }
+}
+
+/// A [`Layer`] that requests one unit from a shared [`KeyedGcr`] for every request, using the
+/// key returned by `key`, and responds with `on_denied` instead of calling the inner service if
+/// it is not allowed.
+///
+/// Since the key is only known once a request is made, this always rejects rather than
+/// applying backpressure.
+///
+/// ```rust
+/// use gcr::{KeyedGcr, KeyedGcrLayer};
+/// use std::time::Duration;
+///
+/// struct Request {
+///     user: String,
+/// }
+///
+/// let rate = KeyedGcr::new(10, Duration::from_secs(1), None).unwrap();
+/// let layer = KeyedGcrLayer::new(
+///     rate,
+///     |request: &Request| request.user.clone(),
+///     |_| String::from("429"),
+/// );
+/// ```
+#[derive(Clone)]
+pub struct KeyedGcrLayer<X, F, K, C: Clock = SystemClock> {
+    /// The limiter shared by every service this layer wraps
+    limiter: Arc<Mutex<KeyedGcr<K, C>>>,
+    /// Gets the key to limit a request by
+    key: X,
+    /// Builds the response for a request that was not allowed
+    on_denied: F,
+}
+
+impl<X, F, K, C: Clock> KeyedGcrLayer<X, F, K, C> {
+    /// Create a new [`KeyedGcrLayer`] that limits requests with `keyed`, using the key returned by
+    /// `key` for each request, and responds to requests that are not allowed with `on_denied`
+    pub fn new<Request, R>(keyed: KeyedGcr<K, C>, key: X, on_denied: F) -> Self
+    where
+        X: Fn(&Request) -> K,
+        F: Fn(GcrRequestError) -> R,
+    {
+        Self {
+            limiter: Arc::new(Mutex::new(keyed)),
+            key,
+            on_denied,
+        }
+    }
+
+    /// Get the shared [`KeyedGcr`], such as to [drop idle keys](KeyedGcr::retain_recent)
+    /// while serving requests
+    pub fn limiter(&self) -> &Arc<Mutex<KeyedGcr<K, C>>> {
+        &self.limiter
+    }
+}
+
+impl<S, X: Clone, F: Clone, K, C: Clock> Layer<S> for KeyedGcrLayer<X, F, K, C> {
+    type Service = KeyedGcrService<S, X, F, K, C>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        KeyedGcrService {
+            inner,
+            limiter: self.limiter.clone(),
+            key: self.key.clone(),
+            on_denied: self.on_denied.clone(),
+        }
+    }
+}
+
+impl<X, F, K: fmt::Debug, C: Clock + fmt::Debug> fmt::Debug for KeyedGcrLayer<X, F, K, C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KeyedGcrLayer")
+            .field("limiter", &self.limiter)
+            .finish_non_exhaustive()
+    }
+}
+
+/// The [`Service`] created by a [`KeyedGcrLayer`]
+#[derive(Clone)]
+pub struct KeyedGcrService<S, X, F, K, C: Clock = SystemClock> {
+    inner: S,
+    limiter: Arc<Mutex<KeyedGcr<K, C>>>,
+    key: X,
+    on_denied: F,
+}
+
+impl<S, X, F, K, C, Request> Service<Request> for KeyedGcrService<S, X, F, K, C>
+where
+    S: Service<Request>,
+    X: Fn(&Request) -> K,
+    F: Fn(GcrRequestError) -> S::Response,
+    K: Hash + Eq + Clone,
+    C: Clock + Clone,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = GcrResponseFuture<S::Future, S::Response>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request) -> Self::Future {
+        // Release the lock before calling the inner service
+        let key = (self.key)(&request);
+        let result = lock(&self.limiter).request(key, 1);
+        match result {
+            Ok(()) => GcrResponseFuture::inner(self.inner.call(request)),
+            Err(error) => GcrResponseFuture::rejected((self.on_denied)(error)),
+        }
+    }
+}
+
+impl<S: fmt::Debug, X, F, K: fmt::Debug, C: Clock + fmt::Debug> fmt::Debug
+    for KeyedGcrService<S, X, F, K, C>
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KeyedGcrService")
+            .field("inner", &self.inner)
+            .field("limiter", &self.limiter)
+            .finish_non_exhaustive()
+    }
+}
+
+pin_project! {
+    /// The [`Future`] returned by [`GcrService`] and [`KeyedGcrService`], which either completes
+    /// with the inner service's response or immediately with the rejection response
+    #[derive(Debug)]
+    pub struct GcrResponseFuture<T, R> {
+        #[pin]
+        state: State<T, R>,
+    }
+}
+
+pin_project! {
+    #[project = StateProj]
+    #[derive(Debug)]
+    enum State<T, R> {
+        Inner { #[pin] future: T },
+        Rejected { response: Option<R> },
+    }
+}
+
+impl<T, R> GcrResponseFuture<T, R> {
+    /// Wait for the inner service's response
+    fn inner(future: T) -> Self {
+        Self {
+            state: State::Inner { future },
+        }
+    }
+
+    /// Complete immediately with the rejection response
+    fn rejected(response: R) -> Self {
+        Self {
+            state: State::Rejected {
+                response: Some(response),
+            },
+        }
+    }
+}
+
+impl<T, R, E> Future for GcrResponseFuture<T, R>
+where
+    T: Future<Output = Result<R, E>>,
+{
+    type Output = Result<R, E>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        match self.project().state.project() {
+            StateProj::Inner { future } => future.poll(cx),
+            StateProj::Rejected { response } => Poll::Ready(Ok(response
+                .take()
+                .expect("GcrResponseFuture was polled after completion"))),
+        }
+    }
+}
@@ -0,0 +1,160 @@
+//! Hooks for observing the decisions made by a [`Gcr`](crate::Gcr).
+
+#[cfg(target_has_atomic = "64")]
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+#[cfg(feature = "std")]
+use std::sync::Arc;
+
+use crate::GcrRequestError;
+
+/// Receives every decision made by a [`Gcr`](crate::Gcr), such as to export metrics.
+///
+/// Attach one with [`Gcr::with_observer`](crate::Gcr::with_observer). Both methods do nothing by
+/// default, and `()` is the observer used when none is attached, so unobserved instances pay
+/// nothing for this.
+///
+/// Requests, reservations, waits, and charges are all observed. [`Gcr::request_up_to`](crate::Gcr::request_up_to)
+/// is observed as a request for the units it granted, and [`Gcr::charge`](crate::Gcr::charge) as
+/// one for the units it charged. Outcomes that consume no units, such as a request for zero units
+/// or a charge that is entirely over the max debt, are never reported as allowed.
+///
+/// Observers are called while the rate limiter is borrowed, so they should be quick. To share
+/// one observer between instances (such as every key of a [`KeyedGcr`](crate::KeyedGcr)), attach
+/// a reference or an [`Arc`] to it.
+pub trait GcrObserver {
+    /// Called when `n` units were consumed, leaving `capacity` units available.
+    ///
+    /// `wait` is how long the caller has to wait before acting on the units, which is only
+    /// non-zero for reserved units.
+    fn on_allowed(&self, n: u64, wait: Duration, capacity: u64) {
+        let _ = (n, wait, capacity);
+    }
+
+    /// Called when a request for `n` units failed with `error`, which consumes nothing. `capacity`
+    /// units are still available.
+    fn on_denied(&self, n: u64, error: &GcrRequestError, capacity: u64) {
+        let _ = (n, error, capacity);
+    }
+}
+
+/// Observes nothing
+impl GcrObserver for () {}
+
+impl<T: GcrObserver + ?Sized> GcrObserver for &T {
+    fn on_allowed(&self, n: u64, wait: Duration, capacity: u64) {
+        (**self).on_allowed(n, wait, capacity);
+    }
+
+    fn on_denied(&self, n: u64, error: &GcrRequestError, capacity: u64) {
+        (**self).on_denied(n, error, capacity);
+    }
+}
+
+#[cfg(feature = "std")]
+impl<T: GcrObserver + ?Sized> GcrObserver for Arc<T> {
+    fn on_allowed(&self, n: u64, wait: Duration, capacity: u64) {
+        (**self).on_allowed(n, wait, capacity);
+    }
+
+    fn on_denied(&self, n: u64, error: &GcrRequestError, capacity: u64) {
+        (**self).on_denied(n, error, capacity);
+    }
+}
+
+/// A [`GcrObserver`] that keeps running totals of the units allowed and denied, and of how long
+/// callers were told to wait.
+///
+/// The totals are updated atomically, so one counter can be shared by any number of instances.
+/// Only available on targets with 64-bit atomics.
+///
+#[cfg_attr(feature = "std", doc = "```rust")]
+#[cfg_attr(not(feature = "std"), doc = "```rust,ignore")]
+/// use gcr::{Gcr, GcrCounter};
+/// use std::{sync::Arc, time::Duration};
+///
+/// let counter = Arc::new(GcrCounter::new());
+/// let mut rate = Gcr::new(10, Duration::from_secs(1), None)
+///     .unwrap()
+///     .with_observer(counter.clone());
+///
+/// rate.request(10).unwrap();
+/// rate.request(5).unwrap_err();
+/// assert_eq!(counter.allowed(), 10);
+/// assert_eq!(counter.denied(), 5);
+/// ```
+#[cfg(target_has_atomic = "64")]
+#[derive(Debug, Default)]
+pub struct GcrCounter {
+    /// The number of units consumed
+    allowed: AtomicU64,
+    /// The number of units in requests that failed
+    denied: AtomicU64,
+    /// The total wait in nanoseconds, saturating at `u64::MAX`
+    wait: AtomicU64,
+}
+
+#[cfg(target_has_atomic = "64")]
+impl GcrCounter {
+    /// Create a new [`GcrCounter`] with every total at zero
+    pub const fn new() -> Self {
+        Self {
+            allowed: AtomicU64::new(0),
+            denied: AtomicU64::new(0),
+            wait: AtomicU64::new(0),
+        }
+    }
+
+    /// Get the total number of units consumed
+    pub fn allowed(&self) -> u64 {
+        self.allowed.load(Ordering::Relaxed)
+    }
+
+    /// Get the total number of units in requests that failed
+    pub fn denied(&self) -> u64 {
+        self.denied.load(Ordering::Relaxed)
+    }
+
+    /// Get the total time callers were told to wait: every [`GcrRequestError::DeniedFor`]
+    /// duration plus the wait for every reserved unit. Saturates at about 584 years
+    pub fn wait(&self) -> Duration {
+        Duration::from_nanos(self.wait.load(Ordering::Relaxed))
+    }
+
+    /// Set every total back to zero
+    pub fn reset(&self) {
+        self.allowed.store(0, Ordering::Relaxed);
+        self.denied.store(0, Ordering::Relaxed);
+        self.wait.store(0, Ordering::Relaxed);
+    }
+
+    /// Add `value` to `total`, saturating instead of wrapping
+    fn add(total: &AtomicU64, value: u64) {
+        let _ = total.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |total| {
+            Some(total.saturating_add(value))
+        });
+    }
+
+    /// Add `duration` to the total wait
+    fn add_wait(&self, duration: Duration) {
+        if !duration.is_zero() {
+            let nanos = u64::try_from(duration.as_nanos()).unwrap_or(u64::MAX);
+            Self::add(&self.wait, nanos);
+        }
+    }
+}
+
+#[cfg(target_has_atomic = "64")]
+impl GcrObserver for GcrCounter {
+    fn on_allowed(&self, n: u64, wait: Duration, _: u64) {
+        Self::add(&self.allowed, n);
+        self.add_wait(wait);
+    }
+
+    fn on_denied(&self, n: u64, error: &GcrRequestError, _: u64) {
+        Self::add(&self.denied, n);
+        if let GcrRequestError::DeniedFor(duration) = error {
+            self.add_wait(*duration);
+        }
+    }
+}
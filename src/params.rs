@@ -0,0 +1,312 @@
+//! The GCRA math shared by every rate limiter in this crate.
+//!
+//! All decisions are made from the theoretical arrival time alone. Times are compared by adding
+//! the delay tolerance to the current time rather than subtracting it from the theoretical arrival
+//! time, so timestamps close to the [`Clock`](crate::Clock)'s origin never underflow.
+//!
+//! Rather than truncating `period / rate` to whole nanoseconds, time is tracked in ticks of
+//! `1 / rate` nanoseconds. A single unit is then exactly `period` (in nanoseconds) ticks long, so
+//! over any window the number of units allowed is exactly `rate * elapsed / period`.
+
+use core::{
+    cmp::{max, min},
+    time::Duration,
+};
+
+use crate::{GcrCreationError, GcrRequestError, InstantField, Timestamp};
+
+/// The number of nanoseconds in a second
+const NANOS_PER_SEC: u128 = 1_000_000_000;
+
+/// Convert a number of nanoseconds to a [`Duration`], returning `None` if it does not fit
+fn duration_from_nanos(nanos: u128) -> Option<Duration> {
+    u64::try_from(nanos / NANOS_PER_SEC)
+        .ok()
+        .map(|secs| Duration::new(secs, (nanos % NANOS_PER_SEC) as u32))
+}
+
+/// Get the greatest common divisor of `a` and `b`
+#[cfg(feature = "std")]
+fn gcd(mut a: u128, mut b: u128) -> u128 {
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    a
+}
+
+/// The parameters of a rate limiter
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct Params {
+    /// The amount of time over which `rate` units are "refilled"
+    pub(crate) period: Duration,
+    /// The number of units "refilled" per `period`
+    pub(crate) rate: u64,
+    /// The maximum number of units to allow in a single request
+    pub(crate) max_burst: u64,
+}
+
+impl Params {
+    /// Validate the user-facing rate, period, and max burst
+    pub(crate) fn new(
+        rate: u64,
+        period: Duration,
+        max_burst: Option<u64>,
+    ) -> Result<Self, GcrCreationError> {
+        if rate == 0 {
+            return Err(GcrCreationError::ZeroRate);
+        }
+        if period.is_zero() {
+            return Err(GcrCreationError::ZeroEmissionInterval);
+        }
+
+        // If not set, the max burst is the rate
+        let params = Self {
+            period,
+            rate,
+            max_burst: max_burst.unwrap_or(rate),
+        };
+
+        // The delay tolerance (period / rate * max_burst) must be representable, both in ticks
+        // and as a duration
+        params
+            .emission_interval()
+            .checked_mul(u128::from(params.max_burst))
+            .and_then(|delay_tolerance| duration_from_nanos(delay_tolerance / u128::from(rate)))
+            .ok_or(GcrCreationError::DelayToleranceOverflow)?;
+
+        Ok(params)
+    }
+
+    /// Get these parameters with the rate multiplied by `numerator / denominator`, keeping the max
+    /// burst.
+    ///
+    /// The rate and period are scaled together and reduced to lowest terms, so the scaled rate is
+    /// exact rather than rounded to whole nanoseconds.
+    #[cfg(feature = "std")]
+    pub(crate) fn scale(
+        &self,
+        numerator: u128,
+        denominator: u128,
+    ) -> Result<Self, GcrCreationError> {
+        let rate = u128::from(self.rate)
+            .checked_mul(numerator)
+            .ok_or(GcrCreationError::DelayToleranceOverflow)?;
+        let period = self
+            .period
+            .as_nanos()
+            .checked_mul(denominator)
+            .ok_or(GcrCreationError::DelayToleranceOverflow)?;
+
+        let divisor = max(gcd(rate, period), 1);
+        let rate =
+            u64::try_from(rate / divisor).map_err(|_| GcrCreationError::DelayToleranceOverflow)?;
+        let period = duration_from_nanos(period / divisor)
+            .ok_or(GcrCreationError::DelayToleranceOverflow)?;
+        Self::new(rate, period, Some(self.max_burst))
+    }
+
+    /// The "refill" rate, in ticks per unit
+    pub(crate) fn emission_interval(&self) -> u128 {
+        self.period.as_nanos()
+    }
+
+    /// The time between the theoretical arrival time and the time at which the next unit is
+    /// allowed, in ticks. This cannot overflow, since it is checked in [`Params::new`]
+    fn delay_tolerance(&self) -> u128 {
+        self.emission_interval() * u128::from(self.max_burst)
+    }
+
+    /// Convert a [`Timestamp`] to ticks. Two `u64`s always multiply to fit in a `u128`
+    pub(crate) fn ticks(&self, timestamp: Timestamp) -> u128 {
+        u128::from(timestamp.as_nanos()) * u128::from(self.rate)
+    }
+
+    /// Convert ticks to a [`Timestamp`], rounding up to the next nanosecond
+    pub(crate) fn timestamp(&self, ticks: u128) -> Result<Timestamp, InstantField> {
+        u64::try_from(ticks.div_ceil(u128::from(self.rate)))
+            .map(Timestamp::from_nanos)
+            .map_err(|_| InstantField::TheoreticalArrivalTime)
+    }
+
+    /// Convert a number of ticks to a [`Duration`], rounding up to the next nanosecond and
+    /// saturating at [`Duration::MAX`]
+    fn duration(&self, ticks: u128) -> Duration {
+        duration_from_nanos(ticks.div_ceil(u128::from(self.rate))).unwrap_or(Duration::MAX)
+    }
+
+    /// Get the theoretical arrival time `duration` after `now`, or `None` if it does not fit
+    #[cfg(feature = "std")]
+    pub(crate) fn after(&self, now: Timestamp, duration: Duration) -> Option<u128> {
+        duration
+            .as_nanos()
+            .checked_mul(u128::from(self.rate))
+            .and_then(|ticks| self.ticks(now).checked_add(ticks))
+    }
+
+    /// Get how long after `now` the theoretical arrival time is, or zero if it has passed
+    pub(crate) fn until(&self, theoretical_arrival_time: u128, now: Timestamp) -> Duration {
+        self.duration(theoretical_arrival_time.saturating_sub(self.ticks(now)))
+    }
+
+    /// Get how long after `now` a single unit is available, or zero if one already is
+    pub(crate) fn next_unit(&self, theoretical_arrival_time: u128, now: Timestamp) -> Duration {
+        self.duration(
+            theoretical_arrival_time
+                .saturating_add(self.emission_interval())
+                .saturating_sub(self.limit(now)),
+        )
+    }
+
+    /// The latest theoretical arrival time that still allows a request at `now`
+    fn limit(&self, now: Timestamp) -> u128 {
+        self.ticks(now).saturating_add(self.delay_tolerance())
+    }
+
+    /// Whether the rate limiter has fully refilled by `now`
+    pub(crate) fn is_idle(&self, theoretical_arrival_time: u128, now: Timestamp) -> bool {
+        theoretical_arrival_time <= self.ticks(now)
+    }
+
+    /// Get the capacity at `now`, given the theoretical arrival time
+    pub(crate) fn capacity(&self, theoretical_arrival_time: u128, now: Timestamp) -> u64 {
+        // Get the duration since the allow at time
+        let Some(time_since) = self.limit(now).checked_sub(theoretical_arrival_time) else {
+            return 0;
+        };
+
+        // Return the min of the number of emission intervals that have passed (units allowed)
+        // and the max burst
+        let units = time_since
+            .checked_div(self.emission_interval())
+            .unwrap_or(u128::MAX);
+        min(units, u128::from(self.max_burst)) as u64
+    }
+
+    /// Consume `n` units at `now`, even if they are not available yet.
+    ///
+    /// Returns the new theoretical arrival time and how long to wait until the units are
+    /// available. If that wait would be longer than `max_wait`, the request is denied for the
+    /// difference.
+    pub(crate) fn reserve(
+        &self,
+        theoretical_arrival_time: u128,
+        now: Timestamp,
+        n: u64,
+        max_wait: Option<Duration>,
+    ) -> Result<(u128, Duration), GcrRequestError> {
+        // If the request is greater than the maximum request size, it can never be allowed
+        if n > self.max_burst {
+            return Err(GcrRequestError::RequestTooLarge);
+        }
+
+        // Calculate how long it would take to allow the request. This is at most the delay
+        // tolerance, so it cannot overflow
+        let required_duration = self.emission_interval() * u128::from(n);
+
+        // Update the theoretical arrival time to account for the new units consumed
+        let new_theoretical_arrival_time = max(theoretical_arrival_time, self.ticks(now))
+            .checked_add(required_duration)
+            .ok_or(GcrRequestError::InstantOverflow {
+                field: InstantField::TheoreticalArrivalTime,
+            })?;
+
+        // Deny the request if we would have to wait too long for it
+        let wait = self.duration(new_theoretical_arrival_time.saturating_sub(self.limit(now)));
+        if let Some(max_wait) = max_wait.filter(|max_wait| wait > *max_wait) {
+            return Err(GcrRequestError::DeniedFor(wait - max_wait));
+        }
+
+        Ok((new_theoretical_arrival_time, wait))
+    }
+
+    /// Consume `n` units at `now` if they are available, returning the new theoretical arrival time
+    pub(crate) fn request(
+        &self,
+        theoretical_arrival_time: u128,
+        now: Timestamp,
+        n: u64,
+    ) -> Result<u128, GcrRequestError> {
+        self.reserve(theoretical_arrival_time, now, n, Some(Duration::ZERO))
+            .map(|(theoretical_arrival_time, _)| theoretical_arrival_time)
+    }
+
+    /// Return `n` units at `now`, returning the new theoretical arrival time.
+    ///
+    /// The theoretical arrival time is never moved before `now`, so this can never result in more
+    /// than the maximum burst being available.
+    pub(crate) fn release(&self, theoretical_arrival_time: u128, now: Timestamp, n: u64) -> u128 {
+        // Calculate how long it would take to allow the units being returned
+        let returned_duration = self.emission_interval().saturating_mul(u128::from(n));
+
+        // Move the theoretical arrival time back, but not past the current time
+        max(
+            theoretical_arrival_time.saturating_sub(returned_duration),
+            self.ticks(now),
+        )
+    }
+
+    /// Consume `n` units at `now` unconditionally, even beyond the maximum burst.
+    ///
+    /// Units consumed beyond the capacity are debt that is paid off as time passes. If
+    /// `max_debt` is set, only as many units are charged as keep the debt within it. Returns the
+    /// new theoretical arrival time and the number of units charged.
+    pub(crate) fn charge(
+        &self,
+        theoretical_arrival_time: u128,
+        now: Timestamp,
+        n: u64,
+        max_debt: Option<u64>,
+    ) -> (u128, u64) {
+        let base = max(theoretical_arrival_time, self.ticks(now));
+
+        // The latest theoretical arrival time the debt allows, which is `max_debt` units past
+        // the point where requests are denied
+        let ceiling = max_debt.map_or(u128::MAX, |max_debt| {
+            self.limit(now).saturating_add(
+                self.emission_interval()
+                    .saturating_mul(u128::from(max_debt)),
+            )
+        });
+
+        // Charge as many units as fit under the ceiling
+        let fits = ceiling
+            .saturating_sub(base)
+            .checked_div(self.emission_interval())
+            .unwrap_or(u128::MAX);
+        let charged = min(u128::from(n), fits) as u64;
+
+        (
+            base.saturating_add(self.emission_interval() * u128::from(charged)),
+            charged,
+        )
+    }
+
+    /// Get the theoretical arrival time under `new` parameters that preserves the capacity at `now`
+    pub(crate) fn rebase(
+        &self,
+        theoretical_arrival_time: u128,
+        now: Timestamp,
+        new: &Params,
+    ) -> Result<u128, GcrCreationError> {
+        match theoretical_arrival_time.checked_sub(self.limit(now)) {
+            // Carry the current capacity over to the new rate
+            None | Some(0) => {
+                let capacity = u128::from(self.capacity(theoretical_arrival_time, now));
+                Ok(new
+                    .limit(now)
+                    .saturating_sub(new.emission_interval().saturating_mul(capacity)))
+            }
+
+            // Units have been reserved ahead of time, so carry them over to the new rate
+            Some(reserved) => {
+                let reserved = reserved.div_ceil(max(self.emission_interval(), 1));
+                new.emission_interval()
+                    .checked_mul(reserved)
+                    .and_then(|reserved| new.limit(now).checked_add(reserved))
+                    .ok_or(GcrCreationError::InstantOverflow {
+                        field: InstantField::TheoreticalArrivalTime,
+                    })
+            }
+        }
+    }
+}
@@ -0,0 +1,200 @@
+//! Units that are given back to a [`Gcr`] unless they are used.
+
+#[cfg(feature = "std")]
+use std::{ops::Deref, sync::Mutex};
+
+#[cfg(feature = "std")]
+use crate::lock;
+use crate::{Clock, Gcr, GcrObserver, GcrRequestError, Timestamp};
+
+/// Units granted by [`Gcr::request_guarded`] that are returned to the [`Gcr`] when dropped,
+/// unless [`Permit::commit`] is called first.
+///
+/// This makes it easy to refund units when the work they were requested for fails, including by
+/// returning early with `?` or panicking.
+///
+/// The [`Gcr`] stays mutably borrowed until the permit is dropped. For a [`Gcr`] shared behind a
+/// [`Mutex`](std::sync::Mutex), use [`Gcr::request_shared`] instead, so the lock is not held
+/// while the work is done.
+///
+#[cfg_attr(feature = "std", doc = "```rust")]
+#[cfg_attr(not(feature = "std"), doc = "```rust,ignore")]
+/// use gcr::Gcr;
+/// use std::time::Duration;
+///
+/// let mut rate = Gcr::new(10, Duration::from_secs(1), None).unwrap();
+///
+/// let permit = rate.request_guarded(4).unwrap();
+/// drop(permit); // The downstream call failed, so the units are refunded
+/// assert_eq!(rate.capacity(), 10);
+///
+/// let permit = rate.request_guarded(4).unwrap();
+/// permit.commit(); // The downstream call succeeded, so the units are kept
+/// assert_eq!(rate.capacity(), 6);
+/// ```
+#[derive(Debug)]
+#[must_use = "the units are refunded as soon as the permit is dropped"]
+pub struct Permit<'a, C: Clock, O: GcrObserver = ()> {
+    /// The instance the units were granted by
+    gcr: &'a mut Gcr<C, O>,
+    /// The number of units to refund on drop, zero once committed
+    n: u64,
+}
+
+impl<C: Clock, O: GcrObserver> Permit<'_, C, O> {
+    /// Get the number of units granted
+    pub fn n(&self) -> u64 {
+        self.n
+    }
+
+    /// Keep the units, so they are not refunded when the permit is dropped
+    pub fn commit(mut self) {
+        self.n = 0;
+    }
+}
+
+impl<C: Clock, O: GcrObserver> Drop for Permit<'_, C, O> {
+    fn drop(&mut self) {
+        if self.n > 0 {
+            self.gcr.release(self.n);
+        }
+    }
+}
+
+impl<C: Clock, O: GcrObserver> Gcr<C, O> {
+    /// Request `n` units from the rate limiter, returning a [`Permit`] that refunds them when
+    /// dropped unless it is committed.
+    ///
+    /// # Errors
+    /// - See [`Gcr::request`]
+    pub fn request_guarded(&mut self, n: u64) -> Result<Permit<'_, C, O>, GcrRequestError> {
+        let now = self.clock.now();
+        self.request_guarded_at(n, now)
+    }
+
+    /// Request `n` units from the rate limiter as if the request was made at `now`, returning a
+    /// [`Permit`] that refunds them when dropped unless it is committed.
+    ///
+    /// Note: the units are refunded at the time they are dropped, read from the [`Clock`].
+    ///
+    /// # Errors
+    /// - See [`Gcr::request`]
+    pub fn request_guarded_at(
+        &mut self,
+        n: u64,
+        now: impl Into<Timestamp>,
+    ) -> Result<Permit<'_, C, O>, GcrRequestError> {
+        self.request_at(n, now)?;
+        Ok(Permit { gcr: self, n })
+    }
+}
+
+/// Units granted by [`Gcr::request_shared`] from a [`Gcr`] behind a [`Mutex`], that are returned
+/// to it when dropped unless [`SharedPermit::commit`] is called first.
+///
+/// Unlike a [`Permit`], the lock is only held while the units are requested and refunded, so
+/// other requests can go through while the work is done. With an [`Arc`](std::sync::Arc), the
+/// permit can also be held across an `.await` or moved to another thread.
+///
+/// ```rust
+/// use gcr::Gcr;
+/// use std::{
+///     sync::{Arc, Mutex},
+///     time::Duration,
+/// };
+///
+/// let rate = Arc::new(Mutex::new(Gcr::new(10, Duration::from_secs(1), None).unwrap()));
+///
+/// let permit = Gcr::request_shared(rate.clone(), 4).unwrap();
+/// Gcr::request_shared(&*rate, 6).unwrap().commit(); // The lock is free in the meantime
+/// drop(permit); // The downstream call failed, so the units are refunded
+/// assert_eq!(rate.lock().unwrap().capacity(), 4);
+/// ```
+#[cfg(feature = "std")]
+#[derive(Debug)]
+#[must_use = "the units are refunded as soon as the permit is dropped"]
+pub struct SharedPermit<M> {
+    /// The lock around the instance the units were granted by, such as a `&Mutex<Gcr>` or an
+    /// `Arc<Mutex<Gcr>>`
+    limiter: M,
+    /// The number of units to refund on drop, zero once committed
+    n: u64,
+    /// Locks the instance and refunds units to it
+    release: fn(&M, u64),
+}
+
+#[cfg(feature = "std")]
+impl<M> SharedPermit<M> {
+    /// Create a new [`SharedPermit`] for `n` units that were granted by the [`Gcr`] behind
+    /// `limiter`
+    fn new<C: Clock, O: GcrObserver>(limiter: M, n: u64) -> Self
+    where
+        M: Deref<Target = Mutex<Gcr<C, O>>>,
+    {
+        Self {
+            limiter,
+            n,
+            release: |limiter, n| lock(limiter).release(n),
+        }
+    }
+
+    /// Get the number of units granted
+    pub fn n(&self) -> u64 {
+        self.n
+    }
+
+    /// Get the lock around the instance the units were granted by
+    pub fn limiter(&self) -> &M {
+        &self.limiter
+    }
+
+    /// Keep the units, so they are not refunded when the permit is dropped
+    pub fn commit(mut self) {
+        self.n = 0;
+    }
+}
+
+#[cfg(feature = "std")]
+impl<M> Drop for SharedPermit<M> {
+    fn drop(&mut self) {
+        if self.n > 0 {
+            (self.release)(&self.limiter, self.n);
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<C: Clock, O: GcrObserver> Gcr<C, O> {
+    /// Request `n` units from the rate limiter behind `limiter`, returning a [`SharedPermit`]
+    /// that refunds them when dropped unless it is committed.
+    ///
+    /// `limiter` can be anything that dereferences to the [`Mutex`], such as a `&Mutex<Gcr>` or
+    /// an `Arc<Mutex<Gcr>>`. It is only locked while requesting and refunding the units.
+    ///
+    /// # Errors
+    /// - See [`Gcr::request`]
+    pub fn request_shared<M: Deref<Target = Mutex<Self>>>(
+        limiter: M,
+        n: u64,
+    ) -> Result<SharedPermit<M>, GcrRequestError> {
+        // Read the clock under the same lock as the request, so no other request can come between
+        lock(&limiter).request(n)?;
+        Ok(SharedPermit::new(limiter, n))
+    }
+
+    /// Request `n` units from the rate limiter behind `limiter` as if the request was made at
+    /// `now`, returning a [`SharedPermit`] that refunds them when dropped unless it is committed.
+    ///
+    /// Note: the units are refunded at the time they are dropped, read from the [`Clock`].
+    ///
+    /// # Errors
+    /// - See [`Gcr::request`]
+    pub fn request_shared_at<M: Deref<Target = Mutex<Self>>>(
+        limiter: M,
+        n: u64,
+        now: impl Into<Timestamp>,
+    ) -> Result<SharedPermit<M>, GcrRequestError> {
+        lock(&limiter).request_at(n, now)?;
+        Ok(SharedPermit::new(limiter, n))
+    }
+}
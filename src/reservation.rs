@@ -0,0 +1,96 @@
+//! Reserving units ahead of time, like a leaky bucket used as a queue.
+
+use core::time::Duration;
+
+use crate::{Clock, Gcr, GcrObserver, GcrRequestError, Timestamp};
+
+/// Units reserved from a [`Gcr`] with [`Gcr::reserve`].
+///
+/// The units have already been consumed. The caller may act once [`Reservation::ready_at`] has
+/// passed, or give the units back with [`Reservation::cancel`]. Reservations cannot be cloned,
+/// so the units can only be given back once.
+#[derive(Debug, PartialEq, Eq)]
+#[must_use = "the reserved units are consumed even if the reservation is unused"]
+pub struct Reservation {
+    /// The number of units reserved
+    n: u64,
+    /// The time at which the reserved units are available
+    ready_at: Timestamp,
+}
+
+impl Reservation {
+    /// Get the number of units reserved
+    pub fn n(&self) -> u64 {
+        self.n
+    }
+
+    /// Get the time at which the caller may act on the reserved units.
+    ///
+    /// With the `std` feature, this can be converted to an [`Instant`](std::time::Instant) if the
+    /// [`Gcr`] uses the [`SystemClock`](crate::SystemClock) or a [`MockClock`](crate::MockClock).
+    pub fn ready_at(&self) -> Timestamp {
+        self.ready_at
+    }
+
+    /// Get how long after `now` the caller has to wait before acting on the reserved units
+    pub fn wait_from(&self, now: impl Into<Timestamp>) -> Duration {
+        self.ready_at.saturating_duration_since(now.into())
+    }
+
+    /// Return the reserved units to `gcr`, which must be the instance they were reserved from.
+    ///
+    /// The returned units never push the capacity of `gcr` above its maximum burst.
+    pub fn cancel<C: Clock, O: GcrObserver>(self, gcr: &mut Gcr<C, O>) {
+        let now = gcr.clock.now();
+        self.cancel_at(gcr, now);
+    }
+
+    /// Return the reserved units to `gcr` as if they were returned at `now`.
+    pub fn cancel_at<C: Clock, O: GcrObserver>(
+        self,
+        gcr: &mut Gcr<C, O>,
+        now: impl Into<Timestamp>,
+    ) {
+        gcr.release_at(self.n, now);
+    }
+}
+
+impl<C: Clock, O: GcrObserver> Gcr<C, O> {
+    /// Set the longest a [`Reservation`] made with [`Gcr::reserve`] is allowed to wait for its units.
+    ///
+    /// By default, reservations may wait for any amount of time.
+    pub fn with_max_wait(mut self, max_wait: Duration) -> Self {
+        self.max_wait = Some(max_wait);
+        self
+    }
+
+    /// Reserve `n` units from the rate limiter, even if they are not available yet.
+    ///
+    /// Unlike [`Gcr::request`], this consumes the units immediately and returns a [`Reservation`]
+    /// with the time at which the caller may act on them.
+    ///
+    /// # Errors
+    /// - [`GcrRequestError::DeniedFor`] - if the wait would be longer than the maximum set with [`Gcr::with_max_wait`]. Includes how much longer than the maximum it would have been.
+    /// - [`GcrRequestError::RequestTooLarge`] - if the request was too large to ever be allowed
+    /// - [`GcrRequestError::InstantOverflow`] - if the theoretical arrival time would be out of range
+    pub fn reserve(&mut self, n: u64) -> Result<Reservation, GcrRequestError> {
+        self.reserve_at(n, self.clock.now())
+    }
+
+    /// Reserve `n` units from the rate limiter as if the reservation was made at `now`.
+    ///
+    /// # Errors
+    /// - See [`Gcr::reserve`]
+    pub fn reserve_at(
+        &mut self,
+        n: u64,
+        now: impl Into<Timestamp>,
+    ) -> Result<Reservation, GcrRequestError> {
+        let now = now.into();
+        let wait = self.reserve_within(n, now, self.max_wait)?;
+        Ok(Reservation {
+            n,
+            ready_at: now.saturating_add(wait),
+        })
+    }
+}
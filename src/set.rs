@@ -0,0 +1,147 @@
+//! A rate limiter that enforces several [`Gcr`] rules at once.
+
+use std::{cmp::max, time::Duration};
+
+use crate::{Clock, Gcr, GcrCreationError, GcrRequestError, SystemClock, Timestamp};
+
+/// A set of [`Gcr`] rules that must all allow a request for it to go through, such as
+/// "10 per second, 300 per minute, and 10,000 per day".
+///
+/// Every rule is checked against the same time before any of them are updated, so a request
+/// denied by one rule never consumes units from the others.
+///
+/// ```rust
+/// use gcr::GcrSet;
+/// use std::time::Duration;
+///
+/// let mut rate = GcrSet::new()
+///     .with_rule(10, Duration::from_secs(1), None)
+///     .unwrap()
+///     .with_rule(15, Duration::from_secs(60), None)
+///     .unwrap();
+///
+/// rate.request(10).unwrap();
+/// rate.request(10).unwrap_err(); // Denied by both rules, for the longer of the two waits
+/// ```
+#[derive(Clone, Debug)]
+pub struct GcrSet<C: Clock = SystemClock> {
+    /// The rules every request is checked against
+    rules: Vec<Gcr<C>>,
+    /// The source of the current time, shared by every rule
+    clock: C,
+}
+
+impl Default for GcrSet {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl GcrSet {
+    /// Create a new, empty [`GcrSet`] that uses the [`SystemClock`]
+    pub fn new() -> Self {
+        Self::with_clock(SystemClock)
+    }
+}
+
+impl<C: Clock + Clone> GcrSet<C> {
+    /// Create a new, empty [`GcrSet`] that reads the current time from `clock`
+    pub fn with_clock(clock: C) -> Self {
+        Self {
+            rules: Vec::new(),
+            clock,
+        }
+    }
+
+    /// Add a rule to the set, starting with its full burst.
+    ///
+    /// Accepts the same parameters as [`Gcr::new`].
+    ///
+    /// # Errors
+    /// - [`GcrCreationError::ZeroRate`] - if the rate was zero
+    /// - [`GcrCreationError::ZeroEmissionInterval`] - if the period was zero
+    /// - [`GcrCreationError::DelayToleranceOverflow`] - if the parameters are out of range
+    pub fn with_rule(
+        mut self,
+        rate: u64,
+        period: Duration,
+        max_burst: Option<u64>,
+    ) -> Result<Self, GcrCreationError> {
+        let rule = Gcr::with_clock(rate, period, max_burst, self.clock.clone())?;
+        self.rules.push(rule);
+        Ok(self)
+    }
+
+    /// Get a reference to the [`Clock`] used by this instance
+    pub fn clock(&self) -> &C {
+        &self.clock
+    }
+
+    /// Get the rules in the set, in the order they were added
+    pub fn rules(&self) -> &[Gcr<C>] {
+        &self.rules
+    }
+
+    /// Get the capacity of the set at a given time, which is the lowest capacity of any rule.
+    ///
+    /// An empty set never limits, so its capacity is `u64::MAX`.
+    ///
+    /// Note: this function calculates the capacity on the fly
+    pub fn capacity_at(&self, now: impl Into<Timestamp>) -> u64 {
+        let now = now.into();
+        self.rules
+            .iter()
+            .map(|rule| rule.capacity_at(now))
+            .min()
+            .unwrap_or(u64::MAX)
+    }
+
+    /// Get the current capacity of the set
+    ///
+    /// Note: this function calculates the capacity on the fly
+    pub fn capacity(&self) -> u64 {
+        self.capacity_at(self.clock.now())
+    }
+
+    /// Request `n` units from every rule in the set.
+    ///
+    /// The units are only consumed if every rule allows the request.
+    ///
+    /// # Errors
+    /// - [`GcrRequestError::DeniedFor`] - if any rule denied the request. Includes the longest duration until every rule would allow a request of the same size.
+    /// - [`GcrRequestError::RequestTooLarge`] - if the request was larger than the maximum burst of any rule
+    /// - [`GcrRequestError::InstantOverflow`] - if the theoretical arrival time of any rule would be out of range
+    pub fn request(&mut self, n: u64) -> Result<(), GcrRequestError> {
+        self.request_at(n, self.clock.now())
+    }
+
+    /// Request `n` units from every rule in the set as if the request was made at `now`.
+    ///
+    /// # Errors
+    /// - See [`GcrSet::request`]
+    pub fn request_at(&mut self, n: u64, now: impl Into<Timestamp>) -> Result<(), GcrRequestError> {
+        let now = now.into();
+
+        // Check every rule before updating any of them, keeping the longest denial
+        let mut denied_for = None;
+        for rule in &self.rules {
+            match rule.params.request(rule.theoretical_arrival_time, now, n) {
+                Ok(_) => {}
+                Err(GcrRequestError::DeniedFor(duration)) => {
+                    denied_for = max(denied_for, Some(duration));
+                }
+                Err(error) => return Err(error),
+            }
+        }
+        if let Some(duration) = denied_for {
+            return Err(GcrRequestError::DeniedFor(duration));
+        }
+
+        // Every rule allows the request, so commit it to all of them
+        for rule in &mut self.rules {
+            rule.request_at(n, now)?;
+        }
+
+        Ok(())
+    }
+}
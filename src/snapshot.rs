@@ -0,0 +1,128 @@
+//! Persisting the state of a [`Gcr`] across process restarts.
+
+use std::time::{Duration, SystemTime};
+
+use crate::{
+    Clock, Gcr, GcrCreationError, GcrObserver, InstantField, Params, SystemClock, Timestamp,
+};
+
+/// The state of a [`Gcr`] at a point in time, created with [`Gcr::snapshot`].
+///
+/// [`Timestamp`]s are only meaningful relative to the [`Clock`] that created them, so the theoretical
+/// arrival time is stored relative to the wall-clock time the snapshot was taken at. With the
+/// `serde` feature enabled, this can be serialized and restored in another process with
+/// [`Gcr::restore`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct GcrSnapshot {
+    /// The number of units "refilled" per `period`
+    rate: u64,
+    /// The amount of time over which `rate` units are "refilled"
+    period: Duration,
+    /// The maximum number of units to allow in a single request
+    max_burst: u64,
+    /// The longest a reservation is allowed to wait for its units
+    max_wait: Option<Duration>,
+    /// The most units `charge` may consume beyond the capacity
+    max_debt: Option<u64>,
+    /// The wall-clock time at which the snapshot was taken
+    taken_at: SystemTime,
+    /// How far the theoretical arrival time was ahead of `taken_at`. Zero if the
+    /// rate limiter had fully refilled
+    theoretical_arrival_time: Duration,
+}
+
+impl GcrSnapshot {
+    /// Get the wall-clock time at which the snapshot was taken
+    pub fn taken_at(&self) -> SystemTime {
+        self.taken_at
+    }
+}
+
+impl Gcr {
+    /// Create a new [`Gcr`] instance from a [`GcrSnapshot`] that uses the [`SystemClock`].
+    ///
+    /// Wall-clock time that passed since the snapshot was taken counts towards refilling the
+    /// rate limiter.
+    ///
+    /// # Errors
+    /// - [`GcrCreationError::InconsistentSnapshot`] - if the snapshot parameters are invalid
+    /// - [`GcrCreationError::InstantOverflow`] - if the restored times are out of range
+    pub fn restore(snapshot: &GcrSnapshot) -> Result<Self, GcrCreationError> {
+        Self::restore_with_clock(snapshot, SystemClock)
+    }
+}
+
+impl<C: Clock, O: GcrObserver> Gcr<C, O> {
+    /// Take a [`GcrSnapshot`] of the current state of the rate limiter
+    pub fn snapshot(&self) -> GcrSnapshot {
+        self.snapshot_at(self.clock.now(), SystemTime::now())
+    }
+
+    /// Take a [`GcrSnapshot`] of the state of the rate limiter at `now`, which corresponds to
+    /// the wall-clock time `wall_now`
+    pub fn snapshot_at(&self, now: impl Into<Timestamp>, wall_now: SystemTime) -> GcrSnapshot {
+        GcrSnapshot {
+            rate: self.params.rate,
+            period: self.params.period,
+            max_burst: self.params.max_burst,
+            max_wait: self.max_wait,
+            max_debt: self.max_debt,
+            taken_at: wall_now,
+            theoretical_arrival_time: self.params.until(self.theoretical_arrival_time, now.into()),
+        }
+    }
+}
+
+impl<C: Clock> Gcr<C> {
+    /// Create a new [`Gcr`] instance from a [`GcrSnapshot`] that reads the current time from `clock`.
+    ///
+    /// # Errors
+    /// - [`GcrCreationError::InconsistentSnapshot`] - if the snapshot parameters are invalid
+    /// - [`GcrCreationError::InstantOverflow`] - if the restored times are out of range
+    pub fn restore_with_clock(snapshot: &GcrSnapshot, clock: C) -> Result<Self, GcrCreationError> {
+        let now = clock.now();
+        Self::restore_at(snapshot, clock, now, SystemTime::now())
+    }
+
+    /// Create a new [`Gcr`] instance from a [`GcrSnapshot`] as if it was restored at `now`, which
+    /// corresponds to the wall-clock time `wall_now`.
+    ///
+    /// # Errors
+    /// - [`GcrCreationError::InconsistentSnapshot`] - if the snapshot parameters are invalid
+    /// - [`GcrCreationError::InstantOverflow`] - if the restored times are out of range
+    pub fn restore_at(
+        snapshot: &GcrSnapshot,
+        clock: C,
+        now: impl Into<Timestamp>,
+        wall_now: SystemTime,
+    ) -> Result<Self, GcrCreationError> {
+        // Make sure the parameters are valid, since the snapshot may have been tampered with
+        let params = Params::new(snapshot.rate, snapshot.period, Some(snapshot.max_burst))
+            .map_err(|_| GcrCreationError::InconsistentSnapshot)?;
+
+        // If the wall clock went backwards, assume no time has passed
+        let elapsed = wall_now
+            .duration_since(snapshot.taken_at)
+            .unwrap_or(Duration::ZERO);
+
+        // Rebase the theoretical arrival time onto our clock
+        let theoretical_arrival_time = params
+            .after(
+                now.into(),
+                snapshot.theoretical_arrival_time.saturating_sub(elapsed),
+            )
+            .ok_or(GcrCreationError::InstantOverflow {
+                field: InstantField::TheoreticalArrivalTime,
+            })?;
+
+        Ok(Self {
+            params,
+            theoretical_arrival_time,
+            max_wait: snapshot.max_wait,
+            max_debt: snapshot.max_debt,
+            clock,
+            observer: (),
+        })
+    }
+}
@@ -0,0 +1,284 @@
+//! Rate limiter state that lives outside of the process, such as in a database shared by replicas.
+
+use core::time::Duration;
+#[cfg(feature = "std")]
+use std::{
+    convert::Infallible,
+    sync::{Arc, Mutex},
+};
+
+#[cfg(not(feature = "std"))]
+use crate::DefaultClock;
+#[cfg(feature = "std")]
+use crate::{lock, WallClock};
+use crate::{Clock, GcrCreationError, GcrRequestError, GcrStoreError, Params, Timestamp};
+
+/// Storage for the theoretical arrival time of a [`StoredGcr`].
+///
+/// A store only needs to support loading the theoretical arrival time and atomically replacing
+/// it if it has not changed since it was loaded. [`StoredGcr`] does all of the math in between,
+/// retrying if another instance updated the store first.
+pub trait GcrStore {
+    /// The error returned when the store could not be read or written
+    type Error;
+
+    /// Load the theoretical arrival time, or `None` if it has never been set
+    fn load(&self) -> Result<Option<Timestamp>, Self::Error>;
+
+    /// Set the theoretical arrival time to `new`, but only if it is still `current`.
+    ///
+    /// Returns whether it was set. This must be atomic with respect to every other instance
+    /// sharing the store.
+    fn compare_and_set(
+        &self,
+        current: Option<Timestamp>,
+        new: Timestamp,
+    ) -> Result<bool, Self::Error>;
+}
+
+impl<S: GcrStore + ?Sized> GcrStore for &S {
+    type Error = S::Error;
+
+    fn load(&self) -> Result<Option<Timestamp>, Self::Error> {
+        (**self).load()
+    }
+
+    fn compare_and_set(
+        &self,
+        current: Option<Timestamp>,
+        new: Timestamp,
+    ) -> Result<bool, Self::Error> {
+        (**self).compare_and_set(current, new)
+    }
+}
+
+#[cfg(feature = "std")]
+impl<S: GcrStore + ?Sized> GcrStore for Arc<S> {
+    type Error = S::Error;
+
+    fn load(&self) -> Result<Option<Timestamp>, Self::Error> {
+        (**self).load()
+    }
+
+    fn compare_and_set(
+        &self,
+        current: Option<Timestamp>,
+        new: Timestamp,
+    ) -> Result<bool, Self::Error> {
+        (**self).compare_and_set(current, new)
+    }
+}
+
+/// A [`GcrStore`] that keeps the theoretical arrival time in memory.
+///
+/// Useful for sharing a limit between [`StoredGcr`]s in the same process (with an [`Arc`]) and as
+/// a stand-in for a shared backend in tests.
+#[cfg(feature = "std")]
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    theoretical_arrival_time: Mutex<Option<Timestamp>>,
+}
+
+#[cfg(feature = "std")]
+impl MemoryStore {
+    /// Create a new, empty [`MemoryStore`]
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[cfg(feature = "std")]
+impl GcrStore for MemoryStore {
+    type Error = Infallible;
+
+    fn load(&self) -> Result<Option<Timestamp>, Self::Error> {
+        Ok(*lock(&self.theoretical_arrival_time))
+    }
+
+    fn compare_and_set(
+        &self,
+        current: Option<Timestamp>,
+        new: Timestamp,
+    ) -> Result<bool, Self::Error> {
+        let mut theoretical_arrival_time = lock(&self.theoretical_arrival_time);
+        if *theoretical_arrival_time != current {
+            return Ok(false);
+        }
+
+        *theoretical_arrival_time = Some(new);
+        Ok(true)
+    }
+}
+
+/// A generic cell rate (GCR) algorithm instance that keeps its state in a [`GcrStore`].
+///
+/// This has the same semantics as [`Gcr`](crate::Gcr), but every request loads the theoretical
+/// arrival time from the store and updates it with a compare-and-set loop, so any number of
+/// instances (such as replicas of a service) can share one limit. A store that has never been
+/// set starts with the full burst available.
+///
+/// Like [`AtomicGcr`](crate::AtomicGcr), the theoretical arrival time is rounded up to the next
+/// whole nanosecond after each request. This can only ever deny units, never allow extra ones.
+///
+/// Instances sharing a store must also share a [`Clock`] origin, since the store holds a
+/// [`Timestamp`]. [`StoredGcr::new`] uses the [`WallClock`], whose origin is the Unix epoch in
+/// every process, so the machines running the replicas only need synchronized clocks.
+///
+#[cfg_attr(feature = "std", doc = "```rust")]
+#[cfg_attr(not(feature = "std"), doc = "```rust,ignore")]
+/// use gcr::{MemoryStore, StoredGcr};
+/// use std::{sync::Arc, time::Duration};
+///
+/// let store = Arc::new(MemoryStore::new());
+/// let replica_a = StoredGcr::new(10, Duration::from_secs(1), None, store.clone()).unwrap();
+/// let replica_b = StoredGcr::new(10, Duration::from_secs(1), None, store).unwrap();
+///
+/// replica_a.request(10).unwrap();
+/// replica_b.request(1).unwrap_err(); // The limit is shared
+/// ```
+#[derive(Clone, Debug)]
+pub struct StoredGcr<
+    S,
+    #[cfg(feature = "std")] C: Clock = WallClock,
+    #[cfg(not(feature = "std"))] C: Clock = DefaultClock,
+> {
+    /// The rate, period, and max burst
+    params: Params,
+    /// Where the theoretical arrival time is kept
+    store: S,
+    /// The source of the current time
+    clock: C,
+}
+
+#[cfg(feature = "std")]
+impl<S: GcrStore> StoredGcr<S> {
+    /// Create a new [`StoredGcr`] instance that uses the [`WallClock`], so replicas in different
+    /// processes agree on the time.
+    ///
+    /// Accepts the same parameters as [`Gcr::new`](crate::Gcr::new), along with the store.
+    ///
+    /// # Errors
+    /// - [`GcrCreationError::ZeroRate`] - if the rate was zero
+    /// - [`GcrCreationError::ZeroEmissionInterval`] - if the period was zero
+    /// - [`GcrCreationError::DelayToleranceOverflow`] - if the parameters are out of range
+    pub fn new(
+        rate: u64,
+        period: Duration,
+        max_burst: Option<u64>,
+        store: S,
+    ) -> Result<Self, GcrCreationError> {
+        Self::with_clock(rate, period, max_burst, store, WallClock)
+    }
+}
+
+impl<S: GcrStore, C: Clock> StoredGcr<S, C> {
+    /// Create a new [`StoredGcr`] instance that reads the current time from `clock`.
+    ///
+    /// Accepts the same parameters as [`Gcr::new`](crate::Gcr::new), along with the store.
+    ///
+    /// # Errors
+    /// - [`GcrCreationError::ZeroRate`] - if the rate was zero
+    /// - [`GcrCreationError::ZeroEmissionInterval`] - if the period was zero
+    /// - [`GcrCreationError::DelayToleranceOverflow`] - if the parameters are out of range
+    pub fn with_clock(
+        rate: u64,
+        period: Duration,
+        max_burst: Option<u64>,
+        store: S,
+        clock: C,
+    ) -> Result<Self, GcrCreationError> {
+        Ok(Self {
+            params: Params::new(rate, period, max_burst)?,
+            store,
+            clock,
+        })
+    }
+
+    /// Get a reference to the [`Clock`] used by this instance
+    pub fn clock(&self) -> &C {
+        &self.clock
+    }
+
+    /// Get a reference to the [`GcrStore`] used by this instance
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    /// Convert a stored theoretical arrival time to ticks. A store that has never been set is
+    /// treated as being at the origin, which always has the full burst available
+    fn ticks(&self, theoretical_arrival_time: Option<Timestamp>) -> u128 {
+        self.params
+            .ticks(theoretical_arrival_time.unwrap_or_default())
+    }
+
+    /// Get the capacity of the rate limiter at a given time.
+    ///
+    /// Note: this function loads the theoretical arrival time from the store
+    ///
+    /// # Errors
+    /// - If the store could not be read
+    pub fn capacity_at(&self, now: impl Into<Timestamp>) -> Result<u64, S::Error> {
+        let theoretical_arrival_time = self.store.load()?;
+        Ok(self
+            .params
+            .capacity(self.ticks(theoretical_arrival_time), now.into()))
+    }
+
+    /// Get the current capacity of the rate limiter
+    ///
+    /// Note: this function loads the theoretical arrival time from the store
+    ///
+    /// # Errors
+    /// - If the store could not be read
+    pub fn capacity(&self) -> Result<u64, S::Error> {
+        self.capacity_at(self.clock.now())
+    }
+
+    /// Request `n` units from the rate limiter.
+    ///
+    /// If the request was allowed through, this will return `Ok(())`. If not, it will return an error with the reason.
+    ///
+    /// # Errors
+    /// - [`GcrStoreError::Request`] - if the request failed. See [`Gcr::request`](crate::Gcr::request)
+    /// - [`GcrStoreError::Store`] - if the store could not be read or written
+    pub fn request(&self, n: u64) -> Result<(), GcrStoreError<S::Error>> {
+        self.request_at(n, self.clock.now())
+    }
+
+    /// Request `n` units from the rate limiter as if the request was made at `now`.
+    ///
+    /// # Errors
+    /// - See [`StoredGcr::request`]
+    pub fn request_at(
+        &self,
+        n: u64,
+        now: impl Into<Timestamp>,
+    ) -> Result<(), GcrStoreError<S::Error>> {
+        let now = now.into();
+
+        loop {
+            // Deny the request if it exceeds capacity, otherwise account for the new units consumed
+            let theoretical_arrival_time = self.store.load().map_err(GcrStoreError::Store)?;
+            let new_theoretical_arrival_time = self
+                .params
+                .request(self.ticks(theoretical_arrival_time), now, n)
+                .map_err(GcrStoreError::Request)?;
+            let new_theoretical_arrival_time = self
+                .params
+                .timestamp(new_theoretical_arrival_time)
+                .map_err(|field| {
+                    GcrStoreError::Request(GcrRequestError::InstantOverflow { field })
+                })?;
+
+            // Only commit if no other instance has updated the store in the meantime. Otherwise,
+            // retry against the new value
+            if self
+                .store
+                .compare_and_set(theoretical_arrival_time, new_theoretical_arrival_time)
+                .map_err(GcrStoreError::Store)?
+            {
+                return Ok(());
+            }
+        }
+    }
+}
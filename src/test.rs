@@ -1,14 +1,53 @@
-use std::{thread::sleep, time::Duration};
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    cell::Cell,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
 
-use crate::{Gcr, GcrRequestError};
+use crate::{
+    AtomicGcr, Clock, Gcr, GcrCreationError, GcrRequestError, GcrSet, GcrTree, GcrTreeError,
+    KeyedGcr, MockClock, Timestamp,
+};
+
+/// An allocator that counts the allocations made on each thread
+struct CountingAllocator;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|allocations| allocations.set(allocations.get() + 1));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+/// Get the number of allocations made on the current thread so far
+fn allocations() -> usize {
+    ALLOCATIONS.with(Cell::get)
+}
 
 #[test]
 fn test_request() {
-    let mut rate: Gcr = Gcr::new(100, Duration::from_millis(100), Some(500))
+    let clock = MockClock::new();
+    let mut rate = Gcr::with_clock(100, Duration::from_millis(100), Some(500), clock.clone())
         .expect("Failed to create GCR instance");
 
     // Make sure we can't request more than the max burst, even if we wait
-    sleep(Duration::from_millis(100));
+    clock.advance(Duration::from_millis(100));
     assert!(matches!(
         rate.request(501),
         Err(GcrRequestError::RequestTooLarge)
@@ -18,27 +57,27 @@ fn test_request() {
     // Make sure we can request up to the burst
     rate.request(500).expect("Failed to request burst");
     assert!(rate.capacity() == 0 && rate.request(1).is_err());
-    assert!(rate.allow_at.elapsed().as_secs() == 0);
+    assert!(
+        Some(rate.theoretical_arrival_time)
+            == rate.params.after(clock.now(), Duration::from_millis(500))
+    );
 
     // Make sure the rate is consistent
-    sleep(Duration::from_millis(100));
-    assert!(rate.capacity() / 10 == 10);
+    clock.advance(Duration::from_millis(100));
+    assert!(rate.capacity() == 100);
 
     // Make sure we are denied for the correct amount of time
-    sleep(Duration::from_millis(100));
+    clock.advance(Duration::from_millis(100));
     let Err(GcrRequestError::DeniedFor(duration)) = rate.request(500) else {
         panic!("Expected a denied for error");
     };
-    assert!(
-        duration.as_millis() / 10 == 29
-            || duration.as_millis() / 10 == 30
-            || duration.as_millis() / 10 == 28
-    );
+    assert!(duration == Duration::from_millis(300));
 }
 
 #[test]
 fn test_adjust() {
-    let mut rate: Gcr = Gcr::new(100, Duration::from_millis(100), Some(500))
+    let clock = MockClock::new();
+    let mut rate = Gcr::with_clock(100, Duration::from_millis(100), Some(500), clock.clone())
         .expect("Failed to create GCR instance");
 
     // Make sure the capacity stays the same when we adjust the parameters
@@ -53,6 +92,1551 @@ fn test_adjust() {
     rate.adjust(200, Duration::from_millis(100), Some(1000))
         .expect("Failed to adjust GCR");
     assert!(rate.capacity() == 300);
-    sleep(Duration::from_millis(200));
-    assert!(rate.capacity() / 100 == 7);
+    clock.advance(Duration::from_millis(200));
+    assert!(rate.capacity() == 700);
+}
+
+#[test]
+fn test_explicit_timestamps() {
+    let clock = MockClock::new();
+    let start = clock.now();
+    let mut rate = Gcr::with_clock(10, Duration::from_secs(1), Some(20), clock.clone())
+        .expect("Failed to create GCR instance");
+
+    // Make sure the supplied time is used instead of the clock
+    rate.request_at(20, start).expect("Failed to request burst");
+    assert!(rate.capacity_at(start) == 0);
+    assert!(rate.capacity_at(start + Duration::from_secs(1)) == 10);
+    let Err(GcrRequestError::DeniedFor(duration)) =
+        rate.request_at(15, start + Duration::from_secs(1))
+    else {
+        panic!("Expected a denied for error");
+    };
+    assert!(duration == Duration::from_millis(500));
+    rate.request_at(15, start + Duration::from_millis(1500))
+        .expect("Failed to request at a later time");
+    assert!(clock.now() == start);
+
+    // Make sure the capacity is preserved at the supplied time
+    rate.adjust_at(
+        20,
+        Duration::from_secs(1),
+        Some(40),
+        start + Duration::from_secs(2),
+    )
+    .expect("Failed to adjust GCR");
+    assert!(rate.capacity_at(start + Duration::from_secs(2)) == 5);
+    assert!(rate.capacity_at(start + Duration::from_secs(3)) == 25);
+}
+
+#[test]
+fn test_mock_clock() {
+    let clock = MockClock::new();
+    let start = clock.now();
+
+    // Make sure clones share the same time
+    let clone = clock.clone();
+    clone.advance(Duration::from_secs(3));
+    assert!(clock.now() == start + Duration::from_secs(3));
+    assert!(clock.elapsed() == Duration::from_secs(3));
+
+    // Make sure the system clock can still be used
+    let mut rate =
+        Gcr::new(10, Duration::from_secs(1), None).expect("Failed to create GCR instance");
+    rate.request(10).expect("Failed to request burst");
+    assert!(rate.request(1).is_err());
+}
+
+#[test]
+fn test_exact_rate() {
+    // Make sure rates that do not divide the period evenly never drift
+    let clock = MockClock::new();
+    let start = clock.now();
+    let window = Duration::from_secs(1_000_000);
+    let rate = Gcr::builder()
+        .rate(7)
+        .per(Duration::from_millis(100))
+        .burst(70_000_000)
+        .initial_capacity(0)
+        .clock(clock.clone())
+        .build()
+        .expect("Failed to build GCR instance");
+    assert!(rate.capacity_at(start + window - Duration::from_nanos(1)) == 69_999_999);
+    assert!(rate.capacity_at(start + window) == 70_000_000);
+
+    // Make sure the atomic variant only rounds in the limiter's favor
+    let atomic = AtomicGcr::with_clock(3, Duration::from_secs(1), Some(3), clock.clone())
+        .expect("Failed to create GCR instance");
+    atomic
+        .request_at(3, start)
+        .expect("Failed to request burst");
+    atomic
+        .request_at(3, start + Duration::from_secs(1))
+        .expect("Failed to request after a period");
+    atomic
+        .request_at(1, start + Duration::from_millis(1334))
+        .expect("Failed to request after an emission interval");
+    assert!(atomic.capacity_at(start + Duration::from_millis(1666)) == 0);
+    assert!(atomic.capacity_at(start + Duration::from_nanos(1_666_666_668)) == 1);
+
+    // Make sure rates above one per nanosecond still limit
+    let mut rate = Gcr::with_clock(3, Duration::from_nanos(2), Some(3), clock.clone())
+        .expect("Failed to create GCR instance");
+    rate.request(3).expect("Failed to request burst");
+    assert!(rate.capacity() == 0);
+    clock.advance(Duration::from_nanos(1));
+    assert!(rate.capacity() == 1);
+    let Err(GcrRequestError::DeniedFor(duration)) = rate.request(3) else {
+        panic!("Expected a denied for error");
+    };
+    assert!(duration == Duration::from_nanos(1));
+}
+
+#[test]
+fn test_large_units() {
+    // Make sure bursts larger than 4 GiB work, like bytes on a 100 Gbit link
+    let clock = MockClock::new();
+    let bytes_per_sec = 12_500_000_000;
+    let mut rate = Gcr::with_clock(
+        bytes_per_sec,
+        Duration::from_secs(1),
+        Some(2 * bytes_per_sec),
+        clock.clone(),
+    )
+    .expect("Failed to create GCR instance");
+    rate.request(2 * bytes_per_sec)
+        .expect("Failed to request burst");
+    clock.advance(Duration::from_millis(500));
+    assert!(rate.capacity() == bytes_per_sec / 2);
+    rate.adjust(
+        2 * bytes_per_sec,
+        Duration::from_secs(1),
+        Some(u64::MAX / 4),
+    )
+    .expect("Failed to adjust GCR");
+    clock.advance(Duration::from_secs(3600));
+    assert!(rate.capacity() == bytes_per_sec / 2 + 3600 * 2 * bytes_per_sec);
+
+    // Make sure parameters that cannot be represented are rejected instead of overflowing
+    assert!(
+        Gcr::new(1, Duration::from_secs(u64::MAX), Some(u64::MAX)).unwrap_err()
+            == GcrCreationError::DelayToleranceOverflow
+    );
+    let mut rate = Gcr::with_clock(u64::MAX, Duration::from_nanos(1), None, clock.clone())
+        .expect("Failed to create GCR instance");
+    rate.request(u64::MAX).expect("Failed to request burst");
+    assert!(rate.capacity() == 0);
+    clock.advance(Duration::from_nanos(1));
+    assert!(rate.capacity() == u64::MAX);
+}
+
+#[test]
+fn test_timestamps() {
+    // Make sure any function can be used as a clock, starting from the origin
+    let now = Cell::new(0);
+    let clock = || Timestamp::from_nanos(now.get());
+    let mut rate = Gcr::with_clock(10, Duration::from_secs(1), Some(20), clock)
+        .expect("Failed to create GCR instance");
+    rate.request(20).expect("Failed to request burst");
+    assert!(rate.capacity() == 0);
+    let Err(GcrRequestError::DeniedFor(duration)) = rate.request(5) else {
+        panic!("Expected a denied for error");
+    };
+    assert!(duration == Duration::from_millis(500));
+    now.set(1_500_000_000);
+    assert!(rate.capacity() == 15);
+
+    // Make sure instants round-trip through timestamps
+    let origin = Instant::from(Timestamp::default());
+    let instant = origin + Duration::from_secs(1);
+    assert!(Timestamp::from(instant) == Timestamp::from_nanos(1_000_000_000));
+    assert!(Instant::from(Timestamp::from(instant)) == instant);
+    assert!(rate.capacity_at(Timestamp::from_nanos(u64::MAX)) == 20);
+
+    // Make sure instants from before the first conversion are not clamped to the origin
+    let now = Instant::now();
+    let earlier = now
+        .checked_sub(Duration::from_secs(60))
+        .expect("Failed to get an earlier instant");
+    let earliest = now
+        .checked_sub(Duration::from_secs(120))
+        .expect("Failed to get an earlier instant");
+    assert!(
+        Timestamp::from(earlier).checked_duration_since(Timestamp::from(earliest))
+            == Some(Duration::from_secs(60))
+    );
+    assert!(Instant::from(Timestamp::from(earliest)) == earliest);
+}
+
+#[test]
+fn test_atomic_request() {
+    let clock = MockClock::new();
+    let rate = AtomicGcr::with_clock(100, Duration::from_millis(100), Some(500), clock.clone())
+        .expect("Failed to create GCR instance");
+
+    // Make sure we behave the same as `Gcr`
+    assert!(matches!(
+        rate.request(501),
+        Err(GcrRequestError::RequestTooLarge)
+    ));
+    assert!(rate.capacity() == 500);
+    rate.request(500).expect("Failed to request burst");
+    assert!(rate.capacity() == 0 && rate.request(1).is_err());
+    clock.advance(Duration::from_millis(200));
+    assert!(rate.capacity() == 200);
+    let Err(GcrRequestError::DeniedFor(duration)) = rate.request(500) else {
+        panic!("Expected a denied for error");
+    };
+    assert!(duration == Duration::from_millis(300));
+
+    // Make sure concurrent requests never exceed the capacity
+    clock.advance(Duration::from_millis(300));
+    let rate = Arc::new(rate);
+    let allowed = Arc::new(AtomicU32::new(0));
+    let handles: Vec<_> = (0..8)
+        .map(|_| {
+            let rate = Arc::clone(&rate);
+            let allowed = Arc::clone(&allowed);
+            thread::spawn(move || {
+                for _ in 0..100 {
+                    if rate.request(1).is_ok() {
+                        allowed.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().expect("Failed to join thread");
+    }
+    assert!(allowed.load(Ordering::Relaxed) == 500);
+    assert!(rate.capacity() == 0);
+}
+
+#[test]
+fn test_keyed() {
+    let clock = MockClock::new();
+    let mut rate = KeyedGcr::with_clock(100, Duration::from_millis(100), Some(500), clock.clone())
+        .expect("Failed to create keyed GCR instance");
+
+    // Make sure keys are limited independently and created lazily
+    assert!(rate.capacity("a") == 500 && rate.is_empty());
+    rate.request("a", 500).expect("Failed to request burst");
+    assert!(rate.request("a", 1).is_err());
+    rate.request("b", 200).expect("Failed to request 200 units");
+    assert!(rate.capacity("a") == 0 && rate.capacity("b") == 300 && rate.len() == 2);
+
+    // Make sure adjusting a single key preserves its capacity and leaves the others alone
+    rate.adjust_key("b", 200, Duration::from_millis(100), Some(1000))
+        .expect("Failed to adjust key");
+    clock.advance(Duration::from_millis(100));
+    assert!(rate.capacity("a") == 100 && rate.capacity("b") == 500);
+
+    // Make sure adjusting every key also applies to new keys
+    rate.adjust_all(50, Duration::from_millis(100), Some(250))
+        .expect("Failed to adjust all keys");
+    assert!(rate.capacity("a") == 100 && rate.capacity("b") == 250);
+    assert!(rate.capacity("c") == 250);
+    assert!(matches!(
+        rate.request("c", 251),
+        Err(GcrRequestError::RequestTooLarge)
+    ));
+
+    // Make sure new keys start with the full burst after it is raised
+    rate.adjust_all(200, Duration::from_millis(100), Some(1000))
+        .expect("Failed to adjust all keys");
+    assert!(rate.capacity("d") == 1000);
+    rate.request("d", 1000).expect("Failed to request burst");
+
+    // Make sure a failed adjustment leaves the template and every key unchanged
+    rate.limiter_at("e", clock.now()).charge(u64::MAX);
+    assert!(matches!(
+        rate.adjust_all(1, Duration::from_secs(1 << 36), Some(1)),
+        Err(GcrCreationError::InstantOverflow { .. })
+    ));
+    assert!(rate.capacity("d") == 0 && rate.capacity("f") == 1000);
+    rate.request("f", 1000).expect("Failed to request burst");
+}
+
+#[test]
+fn test_keyed_eviction() {
+    let clock = MockClock::new();
+    let mut rate = KeyedGcr::with_clock(100, Duration::from_millis(100), Some(500), clock.clone())
+        .expect("Failed to create keyed GCR instance")
+        .with_max_keys(2);
+
+    // Make sure only idle keys are dropped
+    rate.request("a", 100).expect("Failed to request 100 units");
+    rate.request("b", 500).expect("Failed to request burst");
+    clock.advance(Duration::from_millis(100));
+    assert!(rate.get("a").is_some_and(|gcr| gcr.is_idle()));
+    assert!(rate.retain_recent() == 1 && rate.len() == 1);
+    assert!(rate.capacity("a") == 500 && rate.capacity("b") == 100);
+
+    // Make sure idle keys are dropped before evicting active ones
+    rate.request("a", 100).expect("Failed to request 100 units");
+    clock.advance(Duration::from_millis(100));
+    rate.request("c", 100).expect("Failed to request 100 units");
+    assert!(rate.len() == 2 && rate.get("a").is_none() && rate.capacity("b") == 200);
+
+    // Make sure the least recently used key is evicted when nothing is idle
+    rate.request("b", 1).expect("Failed to request 1 unit");
+    rate.request("d", 100).expect("Failed to request 100 units");
+    assert!(rate.len() == 2 && rate.get("c").is_none());
+    assert!(rate.get("b").is_some() && rate.get("d").is_some());
+    assert!(rate.shrink() == 0);
+
+    // Make sure keys adjusted with their own parameters are kept, even once idle
+    rate.adjust_key("b", 10, Duration::from_millis(100), Some(50))
+        .expect("Failed to adjust key");
+    clock.advance(Duration::from_secs(10));
+    assert!(rate.retain_recent() == 1 && rate.get("b").is_some());
+    assert!(rate.capacity("b") == 50);
+
+    // Make sure a flood of new keys stays within the limit, evicting in order of use
+    let mut rate = KeyedGcr::with_clock(1, Duration::from_secs(1), None, clock.clone())
+        .expect("Failed to create keyed GCR instance")
+        .with_max_keys(100);
+    for key in 0..1000 {
+        rate.request(key, 1).expect("Failed to request 1 unit");
+    }
+    assert!(rate.len() == 100);
+    assert!((900..1000).all(|key| rate.get(&key).is_some()));
+}
+
+#[test]
+fn test_set() {
+    let clock = MockClock::new();
+    let start = clock.now();
+    let mut rate = GcrSet::with_clock(clock.clone())
+        .with_rule(10, Duration::from_secs(1), None)
+        .expect("Failed to add rule")
+        .with_rule(15, Duration::from_secs(60), None)
+        .expect("Failed to add rule");
+    assert!(rate.rules().len() == 2 && rate.capacity() == 10);
+
+    // Make sure a request denied by one rule does not consume from the others
+    rate.request(10).expect("Failed to request burst");
+    clock.advance(Duration::from_secs(1));
+    let Err(GcrRequestError::DeniedFor(duration)) = rate.request(10) else {
+        panic!("Expected a denied for error");
+    };
+    assert!(duration == Duration::from_secs(19));
+    assert!(rate.rules()[0].capacity() == 10 && rate.rules()[1].capacity() == 5);
+
+    // Make sure the longest denial is returned and every rule is checked at the same time
+    let Err(GcrRequestError::DeniedFor(duration)) = rate.request_at(6, start) else {
+        panic!("Expected a denied for error");
+    };
+    assert!(duration == Duration::from_secs(4));
+    assert!(matches!(
+        rate.request(11),
+        Err(GcrRequestError::RequestTooLarge)
+    ));
+    rate.request(5).expect("Failed to request 5 units");
+    assert!(rate.capacity() == 0);
+
+    // Make sure an empty set never limits
+    let mut empty = GcrSet::with_clock(clock.clone());
+    empty
+        .request(u64::MAX)
+        .expect("Failed to request from empty set");
+    assert!(empty.capacity() == u64::MAX);
+}
+
+#[test]
+fn test_tree() {
+    let clock = MockClock::new();
+    let second = Duration::from_secs(1);
+    let mut rate =
+        GcrTree::with_clock(100, second, None, clock.clone()).expect("Failed to create GCR tree");
+    rate.insert(&[], "acme", 20, second, None)
+        .expect("Failed to insert tenant");
+    for user in ["alice", "bob", "carol"] {
+        rate.insert(&["acme"], user, 10, second, None)
+            .expect("Failed to insert user");
+    }
+
+    // Make sure requests consume from every level, and report the level with the longest denial
+    rate.request(&["acme", "alice"], 10)
+        .expect("Failed to request from alice");
+    rate.request(&["acme", "bob"], 10)
+        .expect("Failed to request from bob");
+    let error = rate.request(&["acme", "bob"], 1).unwrap_err();
+    assert!(
+        error
+            == GcrTreeError::Request {
+                depth: 2,
+                error: GcrRequestError::DeniedFor(Duration::from_millis(100))
+            }
+    );
+    let error = rate.request(&["acme", "carol"], 1).unwrap_err();
+    assert!(
+        error
+            == GcrTreeError::Request {
+                depth: 1,
+                error: GcrRequestError::DeniedFor(Duration::from_millis(50))
+            }
+    );
+    assert!(error.to_string() == "Request denied for 50ms at depth 1");
+
+    // Make sure denied requests consume nothing, and other errors take priority over denials
+    let capacity = |rate: &GcrTree<&str, MockClock>, path: &[&str]| {
+        rate.get(path).expect("Failed to get GCR").capacity()
+    };
+    assert!(capacity(&rate, &[]) == 80 && capacity(&rate, &["acme", "carol"]) == 10);
+    assert!(matches!(
+        rate.request(&["acme", "carol"], 11),
+        Err(GcrTreeError::Request {
+            depth: 2,
+            error: GcrRequestError::RequestTooLarge
+        })
+    ));
+
+    // Make sure paths are validated
+    assert!(rate.request(&["acme", "dave"], 1) == Err(GcrTreeError::UnknownPath));
+    assert!(rate.insert(&["nope"], "dave", 10, second, None) == Err(GcrTreeError::UnknownPath));
+    assert!(rate.insert(&["acme"], "bob", 10, second, None) == Err(GcrTreeError::PathExists));
+    assert!(rate.remove(&["acme", "bob"]).is_some() && rate.get(&["acme", "bob"]).is_none());
+    assert!(rate.remove::<&str>(&[]).is_none());
+
+    // Make sure adjustments can be propagated to children, preserving their capacity
+    clock.advance(second);
+    rate.adjust(&["acme"], 10, second, None, true)
+        .expect("Failed to adjust tenant");
+    assert!(capacity(&rate, &["acme"]) == 10 && capacity(&rate, &["acme", "alice"]) == 10);
+    rate.request(&["acme", "alice"], 10)
+        .expect("Failed to request from alice");
+    clock.advance(second);
+    assert!(capacity(&rate, &["acme"]) == 10 && capacity(&rate, &["acme", "alice"]) == 5);
+
+    // Make sure scaled rates are exact rather than truncated to whole nanoseconds
+    let mut rate =
+        GcrTree::with_clock(1, second, None, clock.clone()).expect("Failed to create GCR tree");
+    rate.insert(&[], "a", 10, second, None)
+        .expect("Failed to insert child");
+    rate.adjust::<&str>(&[], 3, second, None, true)
+        .expect("Failed to adjust root");
+    let params = |rate: &GcrTree<&str, MockClock>, path: &[&str]| {
+        rate.get(path).expect("Failed to get GCR").params
+    };
+    let scaled = params(&rate, &["a"]);
+    assert!(u128::from(scaled.rate) * second.as_nanos() == 30 * scaled.period.as_nanos());
+
+    // Make sure overflowing adjustments fail without changing any limiter
+    rate.insert(&[], "b", u64::MAX, Duration::from_nanos(1), Some(1))
+        .expect("Failed to insert child");
+    let before = [&[][..], &["a"], &["b"]].map(|path| params(&rate, path));
+    assert!(matches!(
+        rate.adjust::<&str>(&[], 6, second, None, true),
+        Err(GcrTreeError::Creation(
+            GcrCreationError::DelayToleranceOverflow
+        ))
+    ));
+    assert!(matches!(
+        rate.adjust::<&str>(
+            &[],
+            u64::MAX / 2,
+            Duration::from_secs(1 << 40),
+            Some(1),
+            true
+        ),
+        Err(GcrTreeError::Creation(
+            GcrCreationError::DelayToleranceOverflow
+        ))
+    ));
+    assert!([&[][..], &["a"], &["b"]].map(|path| params(&rate, path)) == before);
+}
+
+#[test]
+fn test_store() {
+    use std::sync::atomic::AtomicBool;
+
+    use crate::{GcrStore, GcrStoreError, MemoryStore, StoredGcr, WallClock};
+
+    let clock = MockClock::new();
+    let store = Arc::new(MemoryStore::new());
+    let replica_a = StoredGcr::with_clock(
+        10,
+        Duration::from_secs(1),
+        None,
+        store.clone(),
+        clock.clone(),
+    )
+    .expect("Failed to create stored GCR instance");
+    let replica_b = StoredGcr::with_clock(
+        10,
+        Duration::from_secs(1),
+        None,
+        store.clone(),
+        clock.clone(),
+    )
+    .expect("Failed to create stored GCR instance");
+
+    // Make sure an empty store starts with the full burst, and the limit is shared
+    assert!(store.load().expect("Failed to load").is_none());
+    assert!(replica_a.capacity().expect("Failed to get capacity") == 10);
+    replica_a.request(6).expect("Failed to request 6 units");
+    replica_b.request(4).expect("Failed to request 4 units");
+    assert!(replica_a.capacity().expect("Failed to get capacity") == 0);
+    let Err(GcrStoreError::Request(GcrRequestError::DeniedFor(duration))) = replica_b.request(1)
+    else {
+        panic!("Expected a denied for error");
+    };
+    assert!(duration == Duration::from_millis(100));
+    clock.advance(Duration::from_millis(500));
+    assert!(replica_b.capacity().expect("Failed to get capacity") == 5);
+
+    /// A store that lets another "replica" take 2 units between a load and compare-and-set when
+    /// `conflict` is set
+    struct ConflictingStore {
+        inner: MemoryStore,
+        conflict: AtomicBool,
+        clock: MockClock,
+    }
+    impl GcrStore for ConflictingStore {
+        type Error = String;
+
+        fn load(&self) -> Result<Option<Timestamp>, String> {
+            Ok(self.inner.load().expect("Failed to load"))
+        }
+
+        fn compare_and_set(
+            &self,
+            current: Option<Timestamp>,
+            new: Timestamp,
+        ) -> Result<bool, String> {
+            if self.conflict.swap(false, Ordering::AcqRel) {
+                let other = StoredGcr::with_clock(
+                    10,
+                    Duration::from_secs(1),
+                    None,
+                    &self.inner,
+                    self.clock.clone(),
+                )
+                .expect("Failed to create stored GCR instance");
+                other.request(2).expect("Failed to request 2 units");
+            }
+            Ok(self
+                .inner
+                .compare_and_set(current, new)
+                .expect("Failed to compare and set"))
+        }
+    }
+
+    // Make sure the request is retried against the new value after a conflict
+    let store = ConflictingStore {
+        inner: MemoryStore::new(),
+        conflict: AtomicBool::new(true),
+        clock: clock.clone(),
+    };
+    let rate = StoredGcr::with_clock(10, Duration::from_secs(1), None, &store, clock.clone())
+        .expect("Failed to create stored GCR instance");
+    rate.request(8).expect("Failed to request 8 units");
+    assert!(rate.capacity().expect("Failed to get capacity") == 0);
+    store.conflict.store(true, Ordering::Release);
+    clock.advance(Duration::from_millis(200));
+    let Err(GcrStoreError::Request(GcrRequestError::DeniedFor(duration))) = rate.request(2) else {
+        panic!("Expected a denied for error");
+    };
+    assert!(duration == Duration::from_millis(200));
+
+    /// A store whose backend is unreachable
+    struct FailingStore;
+    impl GcrStore for FailingStore {
+        type Error = &'static str;
+
+        fn load(&self) -> Result<Option<Timestamp>, &'static str> {
+            Err("unreachable")
+        }
+
+        fn compare_and_set(
+            &self,
+            _: Option<Timestamp>,
+            _: Timestamp,
+        ) -> Result<bool, &'static str> {
+            Err("unreachable")
+        }
+    }
+
+    // Make sure store errors are returned
+    let rate = StoredGcr::with_clock(10, Duration::from_secs(1), None, FailingStore, clock)
+        .expect("Failed to create stored GCR instance");
+    assert!(rate.request(1) == Err(GcrStoreError::Store("unreachable")));
+    assert!(rate.capacity() == Err("unreachable"));
+
+    // Make sure replicas in different processes agree on stored times. Replica B emulates a
+    // process with its own monotonic origin, which reads the wall clock once when it starts
+    let store = Arc::new(MemoryStore::new());
+    let replica_a = StoredGcr::new(10, Duration::from_secs(1), None, store.clone())
+        .expect("Failed to create stored GCR instance");
+    let origin = Instant::now();
+    let started = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .expect("Failed to get time since epoch");
+    let process_b = move || Timestamp::from_nanos((started + origin.elapsed()).as_nanos() as u64);
+    let replica_b =
+        StoredGcr::with_clock(10, Duration::from_secs(1), None, store.clone(), process_b)
+            .expect("Failed to create stored GCR instance");
+    replica_a.request(10).expect("Failed to request 10 units");
+    let stored = store
+        .load()
+        .expect("Failed to load")
+        .expect("Store was not set");
+    assert!(WallClock.now().saturating_duration_since(stored) < Duration::from_secs(1));
+    assert!(stored.saturating_duration_since(WallClock.now()) < Duration::from_secs(1));
+    let Err(GcrStoreError::Request(GcrRequestError::DeniedFor(duration))) = replica_b.request(1)
+    else {
+        panic!("Expected a denied for error");
+    };
+    assert!(duration <= Duration::from_millis(100));
+}
+
+#[test]
+fn test_observer() {
+    use std::sync::Mutex;
+
+    use crate::{GcrCounter, GcrObserver};
+
+    let clock = MockClock::new();
+    let counter = Arc::new(GcrCounter::new());
+    let mut rate = Gcr::with_clock(10, Duration::from_secs(1), None, clock.clone())
+        .expect("Failed to create GCR instance")
+        .with_observer(counter.clone());
+
+    // Make sure allowed and denied units are counted, along with how long they were denied for
+    rate.request(8).expect("Failed to request 8 units");
+    let Err(GcrRequestError::DeniedFor(duration)) = rate.request(5) else {
+        panic!("Expected a denied for error");
+    };
+    assert!(duration == Duration::from_millis(300));
+    assert!(rate.request(11) == Err(GcrRequestError::RequestTooLarge));
+    assert!(counter.allowed() == 8);
+    assert!(counter.denied() == 16);
+    assert!(counter.wait() == Duration::from_millis(300));
+
+    // Make sure reservations and partial requests count the units they consume
+    let reservation = rate.reserve(4).expect("Failed to reserve 4 units");
+    assert!(rate.request_up_to(5) == 0);
+    reservation.cancel(&mut rate);
+    assert!(rate.request_up_to(5) == 2);
+    assert!(counter.allowed() == 14);
+    assert!(counter.denied() == 16);
+    assert!(counter.wait() == Duration::from_millis(500));
+    counter.reset();
+    assert!(counter.allowed() == 0 && counter.denied() == 0 && counter.wait() == Duration::ZERO);
+
+    /// The units, outcome, and remaining capacity of a decision
+    type Decision = (u64, Result<Duration, GcrRequestError>, u64);
+
+    /// An observer that records every decision
+    #[derive(Clone, Default)]
+    struct Recorder {
+        decisions: Arc<Mutex<Vec<Decision>>>,
+    }
+    impl GcrObserver for Recorder {
+        fn on_allowed(&self, n: u64, wait: Duration, capacity: u64) {
+            let mut decisions = self.decisions.lock().expect("Failed to lock decisions");
+            decisions.push((n, Ok(wait), capacity));
+        }
+
+        fn on_denied(&self, n: u64, error: &GcrRequestError, capacity: u64) {
+            let mut decisions = self.decisions.lock().expect("Failed to lock decisions");
+            decisions.push((n, Err(error.clone()), capacity));
+        }
+    }
+
+    // Make sure every key of a keyed limiter shares the observer, and the remaining capacity is
+    // reported after each decision
+    let recorder = Recorder::default();
+    let mut rate = KeyedGcr::with_clock(10, Duration::from_secs(1), None, clock.clone())
+        .expect("Failed to create keyed GCR instance")
+        .with_observer(recorder.clone());
+    rate.request("alice", 7).expect("Failed to request 7 units");
+    rate.request("bob", 1).expect("Failed to request 1 unit");
+    assert!(rate.request("alice", 4).is_err());
+    assert!(
+        *recorder.decisions.lock().expect("Failed to lock decisions")
+            == vec![
+                (7, Ok(Duration::ZERO), 3),
+                (1, Ok(Duration::ZERO), 9),
+                (
+                    4,
+                    Err(GcrRequestError::DeniedFor(Duration::from_millis(100))),
+                    3
+                ),
+            ]
+    );
+
+    // Make sure outcomes that consume no units are not reported as allowed
+    let recorder = Recorder::default();
+    let mut rate = Gcr::with_clock(10, Duration::from_secs(1), None, clock.clone())
+        .expect("Failed to create GCR instance")
+        .with_max_debt(0)
+        .with_observer(recorder.clone());
+    rate.request(0).expect("Failed to request 0 units");
+    assert!(rate.charge(10) == 10);
+    assert!(rate.request_up_to(5) == 0);
+    assert!(rate.charge(5) == 0);
+    assert!(
+        *recorder.decisions.lock().expect("Failed to lock decisions")
+            == vec![(10, Ok(Duration::ZERO), 0)]
+    );
+}
+
+#[test]
+fn test_rate_limit_info() {
+    use crate::{RATE_LIMIT_LIMIT, RATE_LIMIT_REMAINING, RATE_LIMIT_RESET, RETRY_AFTER};
+
+    let clock = MockClock::new();
+    let mut rate = Gcr::with_clock(10, Duration::from_secs(1), Some(30), clock.clone())
+        .expect("Failed to create GCR instance");
+
+    // Make sure a full limiter needs no waiting
+    let info = rate.rate_limit_info();
+    assert!(info.limit() == 30 && info.remaining() == 30);
+    assert!(info.reset_after() == Duration::ZERO && info.retry_after() == Duration::ZERO);
+
+    // Make sure the reset is rounded up to whole seconds
+    rate.request(25).expect("Failed to request 25 units");
+    let info = rate.rate_limit_info();
+    assert!(info.remaining() == 5);
+    assert!(info.reset_after() == Duration::from_millis(2500));
+    assert!(info.retry_after() == Duration::ZERO);
+    assert!(
+        info.allowed_headers()
+            == [
+                (RATE_LIMIT_LIMIT, 30),
+                (RATE_LIMIT_REMAINING, 5),
+                (RATE_LIMIT_RESET, 3)
+            ]
+    );
+
+    // Make sure denied headers use the duration of the denial
+    let error = rate
+        .request(10)
+        .expect_err("Expected the request to be denied");
+    let info = rate.rate_limit_info();
+    assert!(info.retry_after() == Duration::ZERO);
+    assert!(
+        info.denied_headers(&error).collect::<Vec<_>>()
+            == vec![
+                (RATE_LIMIT_LIMIT, 30),
+                (RATE_LIMIT_REMAINING, 5),
+                (RATE_LIMIT_RESET, 3),
+                (RETRY_AFTER, 1),
+            ]
+    );
+
+    // Make sure the retry after is the time until the next unit once the limiter is empty
+    rate.request(5).expect("Failed to request 5 units");
+    clock.advance(Duration::from_millis(50));
+    let info = rate.rate_limit_info();
+    assert!(info.remaining() == 0);
+    assert!(info.retry_after() == Duration::from_millis(50));
+    assert!(info.reset_after() == Duration::from_millis(2950));
+
+    // Make sure requests that can never be allowed do not get a retry after
+    let error = rate
+        .request(31)
+        .expect_err("Expected the request to be too large");
+    assert!(info
+        .denied_headers(&error)
+        .all(|(name, _)| name != RETRY_AFTER));
+
+    // Make sure times that are not whole nanoseconds are rounded up
+    let mut rate = Gcr::with_clock(3, Duration::from_secs(1), Some(1), clock)
+        .expect("Failed to create GCR instance");
+    rate.request(1).expect("Failed to request 1 unit");
+    let info = rate.rate_limit_info();
+    assert!(info.retry_after() == Duration::from_nanos(333_333_334));
+    assert!(info.allowed_headers()[2] == (RATE_LIMIT_RESET, 1));
+}
+
+#[test]
+fn test_wait() {
+    let clock = MockClock::new();
+    let start = clock.now();
+    let mut rate = Gcr::with_clock(100, Duration::from_millis(100), Some(500), clock.clone())
+        .expect("Failed to create GCR instance");
+
+    // Make sure we wait exactly as long as required
+    rate.wait(500).expect("Failed to wait for burst");
+    assert!(clock.now() == start);
+    rate.wait(200).expect("Failed to wait for 200 units");
+    assert!(clock.now() == start + Duration::from_millis(200));
+    assert!(rate.capacity() == 0);
+
+    // Make sure requests that can never be allowed return immediately
+    assert!(matches!(
+        rate.wait(501),
+        Err(GcrRequestError::RequestTooLarge)
+    ));
+    assert!(clock.now() == start + Duration::from_millis(200));
+}
+
+#[test]
+fn test_throttled_io() {
+    use std::io::{self, Read, Write};
+
+    use crate::{ThrottledReader, ThrottledWriter};
+
+    /// A writer that accepts at most 4 bytes per write, or fails if `fail` is set
+    struct ShortWriter {
+        written: usize,
+        fail: bool,
+    }
+
+    impl Write for ShortWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.fail {
+                return Err(io::Error::other("Failed to write"));
+            }
+            let n = buf.len().min(4);
+            self.written += n;
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let clock = MockClock::new();
+    let start = clock.now();
+    let rate = Gcr::with_clock(10, Duration::from_secs(1), None, clock.clone())
+        .expect("Failed to create GCR instance");
+
+    // Make sure reads are split into chunks of the max burst and only read bytes are paid for
+    let mut reader = ThrottledReader::new(&[0; 35][..], rate);
+    let mut buf = Vec::new();
+    reader.read_to_end(&mut buf).expect("Failed to read");
+    assert!(buf.len() == 35);
+    assert!(clock.now() == start + Duration::from_millis(2500));
+
+    // Make sure streams sharing a limiter share its capacity
+    clock.advance(Duration::from_secs(1));
+    let mut writer = ThrottledWriter::shared(Vec::new(), reader.limiter().clone());
+    writer.write_all(&[0; 25]).expect("Failed to write");
+    assert!(writer.get_ref().len() == 25);
+    assert!(clock.now() == start + Duration::from_millis(5000));
+
+    // Make sure bytes that were not written are released
+    clock.advance(Duration::from_secs(1));
+    let limiter = writer.limiter().clone();
+    let mut writer = ThrottledWriter::shared(
+        ShortWriter {
+            written: 0,
+            fail: false,
+        },
+        limiter.clone(),
+    );
+    assert!(writer.write(&[0; 10]).expect("Failed to write") == 4);
+    assert!(limiter.lock().expect("Failed to lock").capacity() == 6);
+    writer.get_mut().fail = true;
+    assert!(writer.write(&[0; 6]).is_err());
+    assert!(limiter.lock().expect("Failed to lock").capacity() == 6);
+    assert!(writer.into_inner().written == 4);
+
+    // Make sure limiters that can never allow a byte fail instead of reporting the end of the stream
+    let rate = Gcr::with_clock(10, Duration::from_secs(1), Some(0), clock.clone())
+        .expect("Failed to create GCR instance");
+    let mut reader = ThrottledReader::new(&[0; 10][..], rate);
+    assert!(reader.read(&mut [0; 10]).is_err());
+}
+
+/// A waker that does nothing, for polling futures that are expected to be ready
+#[cfg(any(feature = "async", feature = "tower", feature = "tokio"))]
+struct NoopWaker;
+
+#[cfg(any(feature = "async", feature = "tower", feature = "tokio"))]
+impl std::task::Wake for NoopWaker {
+    fn wake(self: Arc<Self>) {}
+}
+
+/// Poll a future that is expected to complete without being woken
+#[cfg(any(feature = "async", feature = "tower", feature = "tokio"))]
+fn block_on<F: std::future::Future>(future: F) -> F::Output {
+    use std::{
+        pin::pin,
+        task::{Context, Poll, Waker},
+    };
+
+    let waker = Waker::from(Arc::new(NoopWaker));
+    let Poll::Ready(output) = pin!(future).poll(&mut Context::from_waker(&waker)) else {
+        panic!("Expected the future to be ready");
+    };
+    output
+}
+
+#[cfg(feature = "tokio")]
+#[test]
+fn test_throttled_async_io() {
+    use std::{
+        future::{pending, poll_fn, ready},
+        pin::Pin,
+        sync::Mutex,
+        task::{Context, Poll, Waker},
+    };
+
+    use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+    use crate::{GcrCounter, ThrottledAsyncRead, ThrottledAsyncWrite};
+
+    let clock = MockClock::new();
+    let start = clock.now();
+    let rate = Gcr::with_clock(10, Duration::from_secs(1), None, clock.clone())
+        .expect("Failed to create GCR instance");
+    let sleep = |duration| {
+        clock.advance(duration);
+        ready(())
+    };
+
+    // Make sure reads are shortened to the capacity instead of exceeding it
+    let mut reader = ThrottledAsyncRead::new(&[0; 35][..], rate, sleep);
+    let mut storage = [0; 25];
+    let mut buf = ReadBuf::new(&mut storage);
+    block_on(poll_fn(|cx| Pin::new(&mut reader).poll_read(cx, &mut buf))).expect("Failed to read");
+    assert!(buf.filled().len() == 10);
+    assert!(clock.now() == start);
+
+    // Make sure we sleep until the next byte is available when there is no capacity
+    block_on(poll_fn(|cx| Pin::new(&mut reader).poll_read(cx, &mut buf))).expect("Failed to read");
+    assert!(buf.filled().len() == 11);
+    assert!(clock.now() == start + Duration::from_millis(100));
+
+    // Make sure a pending wait consumes nothing and is polled again instead of restarted
+    let waits = Mutex::new(Vec::new());
+    let limiter = reader.limiter().clone();
+    let mut writer = ThrottledAsyncWrite::shared(Vec::new(), limiter.clone(), |duration| {
+        waits.lock().expect("Failed to lock").push(duration);
+        pending::<()>()
+    });
+    let waker = Waker::from(Arc::new(NoopWaker));
+    let mut cx = Context::from_waker(&waker);
+    assert!(Pin::new(&mut writer)
+        .poll_write(&mut cx, &[0; 5])
+        .is_pending());
+    assert!(Pin::new(&mut writer)
+        .poll_write(&mut cx, &[0; 5])
+        .is_pending());
+    assert!(*waits.lock().expect("Failed to lock") == [Duration::from_millis(100)]);
+    assert!(writer.get_ref().is_empty());
+
+    // Make sure streams sharing a limiter share its capacity, and writes are shortened too
+    clock.advance(Duration::from_secs(1));
+    let mut writer = ThrottledAsyncWrite::shared(Vec::new(), limiter.clone(), sleep);
+    let written = block_on(poll_fn(|cx| Pin::new(&mut writer).poll_write(cx, &[0; 25])))
+        .expect("Failed to write");
+    assert!(written == 10 && writer.get_ref().len() == 10);
+    assert!(limiter.lock().expect("Failed to lock").capacity() == 0);
+
+    // Make sure limiters that can never allow a byte fail instead of waiting forever
+    let rate = Gcr::with_clock(10, Duration::from_secs(1), Some(0), clock.clone())
+        .expect("Failed to create GCR instance");
+    let mut reader = ThrottledAsyncRead::new(&[0; 10][..], rate, sleep);
+    let mut buf = ReadBuf::new(&mut storage);
+    assert!(block_on(poll_fn(|cx| Pin::new(&mut reader).poll_read(cx, &mut buf))).is_err());
+
+    /// A stream that is not ready for the first `pending` polls, then accepts every byte
+    struct Slow {
+        pending: usize,
+        written: usize,
+    }
+    impl AsyncWrite for Slow {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<std::io::Result<usize>> {
+            if self.pending > 0 {
+                self.pending -= 1;
+                return Poll::Pending;
+            }
+            self.written += buf.len();
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    // Make sure a stream that is not ready holds on to its granted bytes instead of requesting
+    // them again on every poll
+    let counter = Arc::new(GcrCounter::new());
+    let rate = Gcr::with_clock(10, Duration::from_secs(1), None, clock.clone())
+        .expect("Failed to create GCR instance")
+        .with_observer(counter.clone());
+    let mut writer = ThrottledAsyncWrite::new(
+        Slow {
+            pending: 3,
+            written: 0,
+        },
+        rate,
+        sleep,
+    );
+    for _ in 0..3 {
+        assert!(Pin::new(&mut writer)
+            .poll_write(&mut cx, &[0; 4])
+            .is_pending());
+    }
+    assert!(counter.allowed() == 4);
+    let limiter = writer.limiter().clone();
+    assert!(limiter.lock().expect("Failed to lock").capacity() == 6);
+    let written = block_on(poll_fn(|cx| Pin::new(&mut writer).poll_write(cx, &[0; 4])))
+        .expect("Failed to write");
+    assert!(written == 4 && writer.get_ref().written == 4 && counter.allowed() == 4);
+
+    // Make sure held bytes are returned if the stream is dropped before it is ready
+    writer.get_mut().pending = 1;
+    assert!(Pin::new(&mut writer)
+        .poll_write(&mut cx, &[0; 4])
+        .is_pending());
+    assert!(limiter.lock().expect("Failed to lock").capacity() == 2);
+    drop(writer);
+    assert!(limiter.lock().expect("Failed to lock").capacity() == 6);
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn test_until_ready() {
+    use std::future::ready;
+
+    let clock = MockClock::new();
+    let start = clock.now();
+    let mut rate = Gcr::with_clock(100, Duration::from_millis(100), Some(500), clock.clone())
+        .expect("Failed to create GCR instance");
+    let sleep = |duration| {
+        clock.advance(duration);
+        ready(())
+    };
+
+    // Make sure we sleep exactly as long as required
+    block_on(rate.until_ready(500, sleep)).expect("Failed to wait for burst");
+    assert!(clock.now() == start);
+    block_on(rate.until_ready(200, sleep)).expect("Failed to wait for 200 units");
+    assert!(clock.now() == start + Duration::from_millis(200));
+
+    // Make sure requests that can never be allowed return immediately
+    assert!(matches!(
+        block_on(rate.until_ready(501, |_| -> std::future::Pending<()> {
+            panic!("Expected not to sleep")
+        })),
+        Err(GcrRequestError::RequestTooLarge)
+    ));
+
+    // Make sure units are reserved before the future is polled, and that adjusting
+    // carries them over to the new rate
+    let future = rate.until_ready(100, sleep);
+    assert!(rate.request(1).is_err());
+    rate.adjust(200, Duration::from_millis(100), Some(1000))
+        .expect("Failed to adjust GCR");
+    drop(future);
+    clock.advance(Duration::from_millis(50));
+    assert!(rate.capacity() == 0);
+    clock.advance(Duration::from_millis(1));
+    assert!(rate.capacity() == 2);
+}
+
+#[cfg(feature = "tower")]
+#[test]
+fn test_tower() {
+    use std::{
+        convert::Infallible,
+        future::{poll_fn, ready, Ready},
+        task::{Context, Poll},
+    };
+
+    use tower_layer::Layer;
+    use tower_service::Service;
+
+    use crate::{GcrBackpressureLayer, GcrLayer, KeyedGcrLayer};
+
+    /// A service that responds with 200 to every request
+    #[derive(Clone)]
+    struct Ok200;
+    impl<Request> Service<Request> for Ok200 {
+        type Response = u16;
+        type Error = Box<dyn std::error::Error + Send + Sync>;
+        type Future = Ready<Result<u16, Self::Error>>;
+
+        fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, _: Request) -> Self::Future {
+            ready(Ok(200))
+        }
+    }
+
+    // Wait for the service to be ready, then make a request
+    fn call<S: Service<Request>, Request>(
+        service: &mut S,
+        request: Request,
+    ) -> Result<S::Response, S::Error> {
+        block_on(poll_fn(|cx| service.poll_ready(cx)))?;
+        block_on(service.call(request))
+    }
+
+    let clock = MockClock::new();
+    let rate = Gcr::with_clock(2, Duration::from_secs(1), None, clock.clone())
+        .expect("Failed to create GCR instance");
+    let on_denied = |error: GcrRequestError| match error {
+        GcrRequestError::DeniedFor(_) => 429,
+        _ => 503,
+    };
+
+    // Make sure denied requests get the rejection response, and clones share the limiter
+    let layer = GcrLayer::new(rate.clone(), on_denied);
+    let mut service = layer.layer(Ok200);
+    let mut other = service.clone();
+    assert!(call(&mut service, ()).expect("Failed to call service") == 200);
+    assert!(call(&mut other, ()).expect("Failed to call service") == 200);
+    assert!(call(&mut service, ()).expect("Failed to call service") == 429);
+    clock.advance(Duration::from_millis(500));
+    assert!(call(&mut other, ()).expect("Failed to call service") == 200);
+
+    // Make sure adjusting the shared limiter applies to every service
+    layer
+        .limiter()
+        .lock()
+        .expect("Failed to lock limiter")
+        .adjust(2, Duration::from_secs(1), Some(0))
+        .expect("Failed to adjust GCR");
+    assert!(call(&mut service, ()).expect("Failed to call service") == 503);
+
+    // Make sure backpressure waits for exactly the denied duration
+    let sleep = |duration| {
+        clock.advance(duration);
+        ready(())
+    };
+    let mut service = GcrBackpressureLayer::new(rate, sleep).layer(Ok200);
+    let start = clock.now();
+    assert!(call(&mut service, ()).expect("Failed to call service") == 200);
+    assert!(call(&mut service, ()).expect("Failed to call service") == 200);
+    assert!(clock.now() == start);
+    assert!(call(&mut service, ()).expect("Failed to call service") == 200);
+    assert!(clock.now() == start + Duration::from_millis(500));
+
+    // Make sure a unit taken by poll_ready is not copied to clones
+    block_on(poll_fn(|cx| Service::<()>::poll_ready(&mut service, cx)))
+        .expect("Failed to poll service");
+    let mut other = service.clone();
+    assert!(call(&mut other, ()).expect("Failed to call service") == 200);
+    assert!(clock.now() == start + Duration::from_millis(1500));
+
+    // Make sure requests that can never be allowed are returned as errors
+    let rate = Gcr::with_clock(2, Duration::from_secs(1), Some(0), clock.clone())
+        .expect("Failed to create GCR instance");
+    let mut service = GcrBackpressureLayer::new(rate, sleep).layer(Ok200);
+    let Err(error) = call(&mut service, ()) else {
+        panic!("Expected a request too large error");
+    };
+    assert!(matches!(
+        error.downcast_ref(),
+        Some(GcrRequestError::RequestTooLarge)
+    ));
+
+    // Make sure each key has its own capacity
+    let keyed = KeyedGcr::with_clock(1, Duration::from_secs(1), None, clock.clone())
+        .expect("Failed to create keyed GCR instance");
+    let mut service =
+        KeyedGcrLayer::new(keyed, |user: &&'static str| *user, on_denied).layer(Ok200);
+    assert!(call(&mut service, "alice").expect("Failed to call service") == 200);
+    assert!(call(&mut service, "alice").expect("Failed to call service") == 429);
+    assert!(call(&mut service, "bob").expect("Failed to call service") == 200);
+
+    // Make sure rejections never reach the inner service, whose error type is irrelevant
+    struct Unreachable;
+    impl Service<()> for Unreachable {
+        type Response = u16;
+        type Error = Infallible;
+        type Future = Ready<Result<u16, Infallible>>;
+
+        fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, _: ()) -> Self::Future {
+            panic!("Expected the request to be rejected")
+        }
+    }
+    let mut rate = Gcr::with_clock(1, Duration::from_secs(1), None, clock.clone())
+        .expect("Failed to create GCR instance");
+    rate.request(1).expect("Failed to request 1 unit");
+    let mut service = GcrLayer::new(rate, |_| 429).layer(Unreachable);
+    assert!(matches!(call(&mut service, ()), Ok(429)));
+}
+
+#[test]
+fn test_reserve() {
+    let clock = MockClock::new();
+    let start = clock.now();
+    let mut rate = Gcr::with_clock(100, Duration::from_millis(100), Some(500), clock.clone())
+        .expect("Failed to create GCR instance")
+        .with_max_wait(Duration::from_millis(300));
+
+    // Make sure reservations consume units and report when they are ready
+    let burst = rate.reserve(500).expect("Failed to reserve burst");
+    assert!(burst.n() == 500 && burst.ready_at() == start);
+    let queued = rate.reserve(200).expect("Failed to reserve 200 units");
+    assert!(queued.ready_at() == start + Duration::from_millis(200));
+    assert!(queued.wait_from(start) == Duration::from_millis(200));
+
+    // Make sure we can't wait longer than the maximum
+    let Err(GcrRequestError::DeniedFor(duration)) = rate.reserve(200) else {
+        panic!("Expected a denied for error");
+    };
+    assert!(duration == Duration::from_millis(100));
+    assert!(matches!(
+        rate.reserve(501),
+        Err(GcrRequestError::RequestTooLarge)
+    ));
+
+    // Make sure cancelling returns the units, but never more than the max burst
+    queued.cancel(&mut rate);
+    clock.advance(Duration::from_millis(200));
+    assert!(rate.capacity() == 200);
+    burst.cancel(&mut rate);
+    assert!(rate.capacity() == 500);
+}
+
+#[test]
+fn test_check() {
+    let clock = MockClock::new();
+    let mut rate = Gcr::with_clock(10, Duration::from_secs(1), Some(30), clock.clone())
+        .expect("Failed to create GCR instance");
+
+    // Make sure checks agree with requests without consuming anything
+    rate.check(30).expect("Failed to check 30 units");
+    assert!(rate.time_until(30) == Some(Duration::ZERO));
+    rate.request(25).expect("Failed to request 25 units");
+    let Err(GcrRequestError::DeniedFor(duration)) = rate.check(10) else {
+        panic!("Expected a denied for error");
+    };
+    assert!(duration == Duration::from_millis(500));
+    assert!(rate.time_until(10) == Some(Duration::from_millis(500)));
+    assert!(rate.capacity() == 5);
+    assert!(rate.request(10) == rate.check(10));
+
+    // Make sure the time until is exact, and requests that are never allowed return `None`
+    clock.advance(Duration::from_millis(499));
+    assert!(rate.time_until(10) == Some(Duration::from_millis(1)));
+    clock.advance(Duration::from_millis(1));
+    assert!(rate.time_until(10) == Some(Duration::ZERO));
+    assert!(rate.check(31) == Err(GcrRequestError::RequestTooLarge));
+    assert!(rate.time_until(31).is_none());
+    rate.request(10).expect("Failed to request 10 units");
+}
+
+#[test]
+fn test_release() {
+    let clock = MockClock::new();
+    let mut rate = Gcr::with_clock(10, Duration::from_secs(1), Some(30), clock.clone())
+        .expect("Failed to create GCR instance");
+
+    // Make sure released units are available again, but never above the max burst
+    rate.request(20).expect("Failed to request 20 units");
+    rate.release(5);
+    assert!(rate.capacity() == 15);
+    rate.release(100);
+    assert!(rate.capacity() == 30);
+    assert!(rate.is_idle());
+
+    // Make sure releasing never moves the theoretical arrival time before now
+    rate.request(30).expect("Failed to request 30 units");
+    clock.advance(Duration::from_secs(3));
+    rate.release(30);
+    assert!(rate.capacity() == 30);
+    rate.request(30).expect("Failed to request 30 units");
+    assert!(rate.request(1).is_err());
+
+    // Make sure permits refund their units unless committed
+    clock.advance(Duration::from_secs(3));
+    let permit = rate
+        .request_guarded(10)
+        .expect("Failed to request 10 units");
+    assert!(permit.n() == 10);
+    drop(permit);
+    assert!(rate.capacity() == 30);
+    rate.request_guarded(10)
+        .expect("Failed to request 10 units")
+        .commit();
+    assert!(rate.capacity() == 20);
+
+    // Make sure permits are refunded when returning early
+    fn work(rate: &mut Gcr<MockClock>) -> Result<(), GcrRequestError> {
+        let permit = rate.request_guarded(10)?;
+        downstream_call()?;
+        permit.commit();
+        Ok(())
+    }
+    fn downstream_call() -> Result<(), GcrRequestError> {
+        Err(GcrRequestError::RequestTooLarge)
+    }
+    assert!(work(&mut rate).is_err());
+    assert!(rate.capacity() == 20);
+
+    // Make sure denied requests do not return a permit
+    assert!(matches!(
+        rate.request_guarded(21),
+        Err(GcrRequestError::DeniedFor(_))
+    ));
+
+    // Make sure shared permits only lock the limiter while requesting and refunding
+    let rate = Arc::new(Mutex::new(rate));
+    let permit = Gcr::request_shared(rate.clone(), 10).expect("Failed to request 10 units");
+    assert!(permit.n() == 10 && rate.try_lock().is_ok());
+    Gcr::request_shared(&*rate, 5)
+        .expect("Failed to request 5 units")
+        .commit();
+    assert!(rate.try_lock().expect("Failed to lock").capacity() == 5);
+
+    // Make sure shared permits can be refunded from another thread
+    thread::spawn(move || drop(permit))
+        .join()
+        .expect("Failed to join thread");
+    assert!(rate.try_lock().expect("Failed to lock").capacity() == 15);
+    assert!(matches!(
+        Gcr::request_shared(&*rate, 16),
+        Err(GcrRequestError::DeniedFor(_))
+    ));
+    assert!(rate.try_lock().expect("Failed to lock").capacity() == 15);
+}
+
+#[test]
+fn test_charge() {
+    let clock = MockClock::new();
+    let mut rate = Gcr::with_clock(10, Duration::from_secs(1), Some(30), clock.clone())
+        .expect("Failed to create GCR instance")
+        .with_max_debt(20);
+
+    // Make sure charges within the capacity behave like requests
+    assert!(rate.charge(25) == 25);
+    assert!(rate.capacity() == 5);
+
+    // Make sure charges beyond the capacity are only limited by the max debt
+    assert!(rate.charge(40) == 25);
+    assert!(rate.capacity() == 0);
+    assert!(rate.charge(1) == 0);
+
+    // Make sure requests are denied until the debt is paid off
+    let Err(GcrRequestError::DeniedFor(duration)) = rate.request(1) else {
+        panic!("Expected a denied for error");
+    };
+    assert!(duration == Duration::from_millis(2100));
+    clock.advance(Duration::from_millis(2000));
+    assert!(rate.request(1).is_err());
+    clock.advance(Duration::from_millis(100));
+    rate.request(1).expect("Failed to request 1 unit");
+
+    // Make sure the debt is unbounded by default
+    let mut rate = Gcr::with_clock(10, Duration::from_secs(1), Some(30), clock.clone())
+        .expect("Failed to create GCR instance");
+    assert!(rate.charge(1000) == 1000);
+    assert!(rate.time_until(1) == Some(Duration::from_millis(97_100)));
+
+    // Make sure the builder sets the max debt
+    let mut rate = Gcr::builder()
+        .rate(10)
+        .per(Duration::from_secs(1))
+        .max_debt(5)
+        .clock(clock.clone())
+        .build()
+        .expect("Failed to build GCR instance");
+    assert!(rate.charge(100) == 15);
+}
+
+#[test]
+fn test_request_up_to() {
+    let clock = MockClock::new();
+    let start = clock.now();
+    let mut rate = Gcr::with_clock(100, Duration::from_millis(100), Some(500), clock.clone())
+        .expect("Failed to create GCR instance");
+
+    // Make sure we grant as much as is available
+    assert!(rate.request_up_to(300) == 300);
+    assert!(rate.request_up_to(300) == 200);
+    assert!(rate.request_up_to(300) == 0);
+
+    // Make sure granted units are consumed exactly like `request`
+    clock.advance(Duration::from_millis(120));
+    assert!(rate.request_up_to(100) == 100);
+    assert!(rate.capacity() == 20);
+    assert!(rate.request_up_to_at(1000, start + Duration::from_millis(600)) == 500);
+    assert!(rate.capacity_at(start + Duration::from_millis(600)) == 0);
+}
+
+#[test]
+fn test_snapshot() {
+    let clock = MockClock::new();
+    let start = clock.now();
+    let wall_start = SystemTime::now();
+    let mut rate = Gcr::with_clock(100, Duration::from_millis(100), Some(500), clock.clone())
+        .expect("Failed to create GCR instance")
+        .with_max_wait(Duration::from_secs(1));
+    rate.request(400).expect("Failed to request 400 units");
+
+    // Make sure the state survives being restored on a different clock
+    let snapshot = rate.snapshot_at(start, wall_start);
+    let other = MockClock::new();
+    let restored = Gcr::restore_at(&snapshot, other.clone(), other.now(), wall_start)
+        .expect("Failed to restore GCR");
+    assert!(restored.capacity() == 100);
+    assert!(restored.snapshot_at(other.now(), wall_start) == snapshot);
+
+    // Make sure wall-clock time that passed counts towards refilling
+    let restored = Gcr::restore_at(
+        &snapshot,
+        other.clone(),
+        other.now(),
+        wall_start + Duration::from_millis(250),
+    )
+    .expect("Failed to restore GCR");
+    assert!(restored.capacity() == 350);
+    let restored = Gcr::restore_at(
+        &snapshot,
+        other.clone(),
+        other.now(),
+        wall_start + Duration::from_secs(60),
+    )
+    .expect("Failed to restore GCR");
+    assert!(restored.capacity() == 500 && restored.is_idle());
+}
+
+#[test]
+fn test_builder() {
+    let clock = MockClock::new();
+
+    // Make sure the builder is equivalent to `Gcr::new`
+    let rate = Gcr::builder()
+        .rate(100)
+        .per(Duration::from_millis(100))
+        .burst(500)
+        .clock(clock.clone())
+        .build()
+        .expect("Failed to build GCR instance");
+    assert!(
+        rate == Gcr::with_clock(100, Duration::from_millis(100), Some(500), clock.clone())
+            .expect("Failed to create GCR instance")
+    );
+
+    // Make sure the emission interval, burst duration and initial capacity are respected
+    let mut rate = Gcr::builder()
+        .emission_interval(Duration::from_millis(1))
+        .burst_duration(Duration::from_millis(500))
+        .initial_capacity(0)
+        .clock(clock.clone())
+        .build()
+        .expect("Failed to build GCR instance");
+    assert!(rate.capacity() == 0);
+    clock.advance(Duration::from_millis(600));
+    assert!(rate.capacity() == 500);
+    assert!(matches!(
+        rate.request(501),
+        Err(GcrRequestError::RequestTooLarge)
+    ));
+
+    // Make sure invalid combinations are rejected
+    let period = Duration::from_secs(1);
+    let interval = Duration::from_millis(1);
+    assert!(Gcr::builder().build() == Err(GcrCreationError::MissingRate));
+    assert!(Gcr::builder().rate(1).build() == Err(GcrCreationError::MissingPeriod));
+    assert!(Gcr::builder().per(period).build() == Err(GcrCreationError::MissingRate));
+    assert!(
+        Gcr::builder()
+            .rate(1)
+            .per(period)
+            .emission_interval(interval)
+            .build()
+            == Err(GcrCreationError::ConflictingRate)
+    );
+    assert!(Gcr::builder().rate(0).per(period).build() == Err(GcrCreationError::ZeroRate));
+    assert!(
+        Gcr::builder().rate(2).per(Duration::ZERO).build()
+            == Err(GcrCreationError::ZeroEmissionInterval)
+    );
+    assert!(
+        Gcr::builder().emission_interval(interval).build() == Err(GcrCreationError::MissingBurst)
+    );
+    assert!(
+        Gcr::builder()
+            .emission_interval(interval)
+            .burst(1)
+            .burst_duration(period)
+            .build()
+            == Err(GcrCreationError::ConflictingBurst)
+    );
+    assert!(
+        Gcr::builder()
+            .emission_interval(interval)
+            .burst_duration(Duration::from_micros(999))
+            .build()
+            == Err(GcrCreationError::BurstDurationTooShort)
+    );
+    assert!(
+        Gcr::builder()
+            .rate(10)
+            .per(period)
+            .initial_capacity(11)
+            .build()
+            == Err(GcrCreationError::InitialCapacityTooLarge {
+                initial_capacity: 11,
+                max_burst: 10
+            })
+    );
+}
+
+#[test]
+fn test_errors() {
+    let clock = MockClock::new();
+    let mut rate = Gcr::with_clock(100, Duration::from_millis(100), Some(500), clock.clone())
+        .expect("Failed to create GCR instance");
+    let atomic = AtomicGcr::with_clock(100, Duration::from_millis(100), Some(500), clock.clone())
+        .expect("Failed to create GCR instance");
+    rate.request(500).expect("Failed to request burst");
+    atomic.request(500).expect("Failed to request burst");
+
+    // Make sure denied requests never allocate
+    let before = allocations();
+    for _ in 0..100 {
+        assert!(matches!(
+            rate.request(1),
+            Err(GcrRequestError::DeniedFor(_))
+        ));
+        assert!(matches!(
+            atomic.request(1),
+            Err(GcrRequestError::DeniedFor(_))
+        ));
+        assert!(matches!(
+            rate.request(501),
+            Err(GcrRequestError::RequestTooLarge)
+        ));
+    }
+    assert!(allocations() == before);
+
+    // Make sure errors can be matched on and used as `std::error::Error`s
+    assert!(Gcr::new(0, Duration::from_secs(1), None).unwrap_err() == GcrCreationError::ZeroRate);
+    assert!(
+        Gcr::new(10, Duration::ZERO, None).unwrap_err() == GcrCreationError::ZeroEmissionInterval
+    );
+    assert!(
+        AtomicGcr::new(10, Duration::ZERO, None).unwrap_err()
+            == GcrCreationError::ZeroEmissionInterval
+    );
+    assert!(
+        crate::StoredGcr::new(10, Duration::ZERO, None, crate::MemoryStore::new()).unwrap_err()
+            == GcrCreationError::ZeroEmissionInterval
+    );
+    assert!(
+        Gcr::new(1, Duration::MAX, Some(2)).unwrap_err()
+            == GcrCreationError::DelayToleranceOverflow
+    );
+    let error: Box<dyn std::error::Error> = Box::new(GcrRequestError::RequestTooLarge);
+    assert!(error.to_string() == "Request was too large to ever be allowed");
 }
@@ -0,0 +1,44 @@
+use core::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use crate::{Gcr, GcrRequestError, Timestamp};
+
+/// The current time of [`read_timer`], in nanoseconds
+static TIMER: AtomicU64 = AtomicU64::new(0);
+
+/// A clock that only needs `core`, standing in for a hardware timer
+fn read_timer() -> Timestamp {
+    Timestamp::from_nanos(TIMER.load(Ordering::SeqCst))
+}
+
+#[test]
+fn test_fn_clock() {
+    let mut rate = Gcr::with_clock(
+        10,
+        Duration::from_secs(1),
+        Some(30),
+        read_timer as fn() -> Timestamp,
+    )
+    .expect("Failed to create rate limiter");
+
+    // Drain the limiter, which starts full
+    rate.request(30).expect("Failed to request units");
+    let Err(GcrRequestError::DeniedFor(duration)) = rate.request(1) else {
+        panic!("Expected a denied for error");
+    };
+    assert!(duration == Duration::from_millis(100));
+
+    // Advancing the timer refills the limiter
+    TIMER.fetch_add(Duration::from_secs(1).as_nanos() as u64, Ordering::SeqCst);
+    assert!(rate.capacity() == 10);
+    rate.request(10).expect("Failed to request units");
+
+    // Doubling the rate preserves the current capacity
+    rate.adjust(20, Duration::from_secs(1), Some(30))
+        .expect("Failed to adjust rate limiter");
+    assert!(rate.capacity() == 0);
+    TIMER.fetch_add(Duration::from_secs(1).as_nanos() as u64, Ordering::SeqCst);
+    assert!(rate.capacity() == 20);
+}
@@ -0,0 +1,336 @@
+//! A hierarchy of [`Gcr`] instances, such as per-user under per-tenant under global.
+
+use std::{borrow::Borrow, collections::HashMap, hash::Hash, time::Duration};
+
+use crate::{
+    Clock, Gcr, GcrCreationError, GcrRequestError, GcrTreeError, Params, SystemClock, Timestamp,
+};
+
+/// A tree of [`Gcr`] instances where a request must be allowed by a limiter and all of its
+/// ancestors.
+///
+/// Limiters are addressed by their path from the root, so `&[]` is the root (such as a global
+/// limit), `&["acme"]` one of its children (such as a tenant), and `&["acme", "alice"]` one of
+/// theirs (such as a user).
+///
+/// ```rust
+/// use gcr::GcrTree;
+/// use std::time::Duration;
+///
+/// let mut rate = GcrTree::new(100, Duration::from_secs(1), None).unwrap();
+/// rate.insert(&[], "acme", 20, Duration::from_secs(1), None).unwrap();
+/// rate.insert(&["acme"], "alice", 10, Duration::from_secs(1), None).unwrap();
+/// rate.insert(&["acme"], "bob", 10, Duration::from_secs(1), None).unwrap();
+///
+/// rate.request(&["acme", "alice"], 10).unwrap();
+/// rate.request(&["acme", "bob"], 10).unwrap();
+/// rate.request(&["acme", "bob"], 1).unwrap_err(); // Denied at depth 2 (bob)
+/// ```
+#[derive(Clone, Debug)]
+pub struct GcrTree<K, C: Clock = SystemClock> {
+    /// The limiter every request goes through
+    root: Node<K, C>,
+    /// The source of the current time, shared by every limiter
+    clock: C,
+}
+
+/// A [`Gcr`] along with the limiters below it
+#[derive(Clone, Debug)]
+struct Node<K, C: Clock> {
+    gcr: Gcr<C>,
+    children: HashMap<K, Node<K, C>>,
+}
+
+impl<K: Hash + Eq, C: Clock> Node<K, C> {
+    /// Create a node without any children
+    fn new(gcr: Gcr<C>) -> Self {
+        Self {
+            gcr,
+            children: HashMap::new(),
+        }
+    }
+
+    /// Get the node at `path` below this one
+    fn get<Q>(&self, path: &[Q]) -> Option<&Self>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq,
+    {
+        path.iter()
+            .try_fold(self, |node, key| node.children.get(key))
+    }
+
+    /// Get the node at `path` below this one, mutably
+    fn get_mut<Q>(&mut self, path: &[Q]) -> Option<&mut Self>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq,
+    {
+        path.iter()
+            .try_fold(self, |node, key| node.children.get_mut(key))
+    }
+
+    /// Scale the rate of every limiter below this one by `numerator / denominator` as if the
+    /// adjustment was made at `now`, preserving their capacity.
+    ///
+    /// Nothing is updated unless `apply` is set, so every limiter can be checked before any of
+    /// them is changed.
+    fn scale_children(
+        &mut self,
+        numerator: u128,
+        denominator: u128,
+        now: Timestamp,
+        apply: bool,
+    ) -> Result<(), GcrCreationError> {
+        for child in self.children.values_mut() {
+            let params = child.gcr.params.scale(numerator, denominator)?;
+            let theoretical_arrival_time =
+                child
+                    .gcr
+                    .params
+                    .rebase(child.gcr.theoretical_arrival_time, now, &params)?;
+            if apply {
+                child.gcr.params = params;
+                child.gcr.theoretical_arrival_time = theoretical_arrival_time;
+            }
+
+            child.scale_children(numerator, denominator, now, apply)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl<K: Hash + Eq> GcrTree<K> {
+    /// Create a new [`GcrTree`] with only a root limiter that uses the [`SystemClock`].
+    ///
+    /// Accepts the same parameters as [`Gcr::new`], which are used for the root.
+    ///
+    /// # Errors
+    /// - [`GcrCreationError::ZeroRate`] - if the rate was zero
+    /// - [`GcrCreationError::ZeroEmissionInterval`] - if the period was zero
+    /// - [`GcrCreationError::DelayToleranceOverflow`] - if the parameters are out of range
+    pub fn new(
+        rate: u64,
+        period: Duration,
+        max_burst: Option<u64>,
+    ) -> Result<Self, GcrCreationError> {
+        Self::with_clock(rate, period, max_burst, SystemClock)
+    }
+}
+
+impl<K: Hash + Eq, C: Clock + Clone> GcrTree<K, C> {
+    /// Create a new [`GcrTree`] with only a root limiter that reads the current time from `clock`.
+    ///
+    /// Accepts the same parameters as [`Gcr::new`], which are used for the root.
+    ///
+    /// # Errors
+    /// - [`GcrCreationError::ZeroRate`] - if the rate was zero
+    /// - [`GcrCreationError::ZeroEmissionInterval`] - if the period was zero
+    /// - [`GcrCreationError::DelayToleranceOverflow`] - if the parameters are out of range
+    pub fn with_clock(
+        rate: u64,
+        period: Duration,
+        max_burst: Option<u64>,
+        clock: C,
+    ) -> Result<Self, GcrCreationError> {
+        Ok(Self {
+            root: Node::new(Gcr::with_clock(rate, period, max_burst, clock.clone())?),
+            clock,
+        })
+    }
+
+    /// Get a reference to the [`Clock`] used by this instance
+    pub fn clock(&self) -> &C {
+        &self.clock
+    }
+
+    /// Add a limiter named `key` below the one at `parent`, starting with its full burst.
+    ///
+    /// Accepts the same parameters as [`Gcr::new`].
+    ///
+    /// # Errors
+    /// - [`GcrTreeError::UnknownPath`] - if there is no limiter at `parent`
+    /// - [`GcrTreeError::PathExists`] - if the limiter at `parent` already has a child named `key`
+    /// - [`GcrTreeError::Creation`] - if the parameters were invalid
+    pub fn insert(
+        &mut self,
+        parent: &[K],
+        key: K,
+        rate: u64,
+        period: Duration,
+        max_burst: Option<u64>,
+    ) -> Result<(), GcrTreeError> {
+        let parent = self.root.get_mut(parent).ok_or(GcrTreeError::UnknownPath)?;
+        if parent.children.contains_key(&key) {
+            return Err(GcrTreeError::PathExists);
+        }
+
+        let gcr = Gcr::with_clock(rate, period, max_burst, self.clock.clone())
+            .map_err(GcrTreeError::Creation)?;
+        parent.children.insert(key, Node::new(gcr));
+
+        Ok(())
+    }
+
+    /// Get the [`Gcr`] at `path`, if there is one
+    pub fn get<Q>(&self, path: &[Q]) -> Option<&Gcr<C>>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq,
+    {
+        self.root.get(path).map(|node| &node.gcr)
+    }
+
+    /// Remove the limiter at `path` along with every limiter below it, returning its [`Gcr`].
+    ///
+    /// The root cannot be removed, so an empty path always returns `None`.
+    pub fn remove<Q>(&mut self, path: &[Q]) -> Option<Gcr<C>>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq,
+    {
+        let (key, parent) = path.split_last()?;
+        self.root
+            .get_mut(parent)?
+            .children
+            .remove(key)
+            .map(|node| node.gcr)
+    }
+
+    /// Request `n` units from the limiter at `path` and every limiter above it.
+    ///
+    /// The units are only consumed if every limiter on the path allows the request.
+    ///
+    /// # Errors
+    /// - [`GcrTreeError::UnknownPath`] - if there is no limiter at `path`
+    /// - [`GcrTreeError::Request`] - if a limiter on the path failed the request, along with its depth (zero being the root). Errors other than [`GcrRequestError::DeniedFor`] are reported first, then the longest denial. Ties go to the limiter closest to `path`.
+    pub fn request<Q>(&mut self, path: &[Q], n: u64) -> Result<(), GcrTreeError>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq,
+    {
+        let now = self.clock.now();
+        self.request_at(path, n, now)
+    }
+
+    /// Request `n` units from the limiter at `path` and every limiter above it as if the
+    /// request was made at `now`.
+    ///
+    /// # Errors
+    /// - See [`GcrTree::request`]
+    pub fn request_at<Q>(
+        &mut self,
+        path: &[Q],
+        n: u64,
+        now: impl Into<Timestamp>,
+    ) -> Result<(), GcrTreeError>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq,
+    {
+        let now = now.into();
+
+        // Check every limiter on the path before updating any of them
+        let mut failure: Option<(usize, GcrRequestError)> = None;
+        let mut node = &self.root;
+        for depth in 0..=path.len() {
+            if let Err(error) = node
+                .gcr
+                .params
+                .request(node.gcr.theoretical_arrival_time, now, n)
+            {
+                // Deeper failures replace shallower ones, unless they are shorter denials
+                let replace = match (&failure, &error) {
+                    (None, _) => true,
+                    (
+                        Some((_, GcrRequestError::DeniedFor(current))),
+                        GcrRequestError::DeniedFor(duration),
+                    ) => duration >= current,
+                    (Some((_, GcrRequestError::DeniedFor(_))), _) => true,
+                    (Some(_), GcrRequestError::DeniedFor(_)) => false,
+                    (Some(_), _) => true,
+                };
+                if replace {
+                    failure = Some((depth, error));
+                }
+            }
+
+            if let Some(key) = path.get(depth) {
+                node = node.children.get(key).ok_or(GcrTreeError::UnknownPath)?;
+            }
+        }
+        if let Some((depth, error)) = failure {
+            return Err(GcrTreeError::Request { depth, error });
+        }
+
+        // Every limiter allows the request, so commit it to all of them
+        let mut node = &mut self.root;
+        for depth in 0..=path.len() {
+            node.gcr
+                .request_at(n, now)
+                .map_err(|error| GcrTreeError::Request { depth, error })?;
+
+            if let Some(key) = path.get(depth) {
+                node = node
+                    .children
+                    .get_mut(key)
+                    .ok_or(GcrTreeError::UnknownPath)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Adjust the parameters of the limiter at `path` while preserving its current capacity.
+    ///
+    /// If `propagate` is set, the rate of every limiter below it is scaled by the same factor as
+    /// its own (for example, halved if its rate was halved), also preserving their capacity.
+    ///
+    /// # Errors
+    /// - [`GcrTreeError::UnknownPath`] - if there is no limiter at `path`
+    /// - [`GcrTreeError::Creation`] - if the parameters, or the scaled parameters of a limiter below it, were invalid. No limiter is changed in that case
+    pub fn adjust<Q>(
+        &mut self,
+        path: &[Q],
+        rate: u64,
+        period: Duration,
+        max_burst: Option<u64>,
+        propagate: bool,
+    ) -> Result<(), GcrTreeError>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq,
+    {
+        // This is the canonical adjustment time
+        let now = self.clock.now();
+
+        let node = self.root.get_mut(path).ok_or(GcrTreeError::UnknownPath)?;
+        let old = node.gcr.params;
+        let params = Params::new(rate, period, max_burst).map_err(GcrTreeError::Creation)?;
+        let theoretical_arrival_time = old
+            .rebase(node.gcr.theoretical_arrival_time, now, &params)
+            .map_err(GcrTreeError::Creation)?;
+
+        if propagate {
+            // The rate is scaled by (rate / period) / (old rate / old period)
+            let numerator = u128::from(rate).checked_mul(old.period.as_nanos()).ok_or(
+                GcrTreeError::Creation(GcrCreationError::DelayToleranceOverflow),
+            )?;
+            let denominator = u128::from(old.rate).checked_mul(period.as_nanos()).ok_or(
+                GcrTreeError::Creation(GcrCreationError::DelayToleranceOverflow),
+            )?;
+
+            // Check every limiter below it before updating any, so a failure changes nothing
+            node.scale_children(numerator, denominator, now, false)
+                .map_err(GcrTreeError::Creation)?;
+            node.scale_children(numerator, denominator, now, true)
+                .map_err(GcrTreeError::Creation)?;
+        }
+
+        node.gcr.params = params;
+        node.gcr.theoretical_arrival_time = theoretical_arrival_time;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,59 @@
+//! Waiting for units to become available instead of handling [`GcrRequestError::DeniedFor`].
+
+#[cfg(feature = "async")]
+use core::future::Future;
+
+use crate::{Clock, Gcr, GcrObserver, GcrRequestError};
+
+impl<C: Clock, O: GcrObserver> Gcr<C, O> {
+    /// Request `n` units from the rate limiter, blocking until they are available.
+    ///
+    /// The units are reserved immediately, so the wait is exactly as long as required. The wait
+    /// is performed with [`Clock::sleep`].
+    ///
+    /// # Errors
+    /// - [`GcrRequestError::RequestTooLarge`] - if the request was too large to ever be allowed. Returned without waiting
+    /// - [`GcrRequestError::InstantOverflow`] - if the theoretical arrival time would be out of range
+    pub fn wait(&mut self, n: u64) -> Result<(), GcrRequestError> {
+        let wait = self.reserve_within(n, self.clock.now(), None)?;
+        if !wait.is_zero() {
+            self.clock.sleep(wait);
+        }
+
+        Ok(())
+    }
+
+    /// Request `n` units from the rate limiter, returning a future that completes once they are available.
+    ///
+    /// The units are reserved immediately, so the returned future does not borrow the [`Gcr`]. It
+    /// waits exactly as long as required by awaiting `sleep`, which lets any async runtime (or a
+    /// test clock) be used:
+    ///
+    /// ```rust,ignore
+    /// rate.until_ready(10, tokio::time::sleep).await?;
+    /// ```
+    ///
+    /// # Errors
+    /// - [`GcrRequestError::RequestTooLarge`] - if the request was too large to ever be allowed. Returned without waiting
+    /// - [`GcrRequestError::InstantOverflow`] - if the theoretical arrival time would be out of range
+    #[cfg(feature = "async")]
+    pub fn until_ready<S, F>(
+        &mut self,
+        n: u64,
+        sleep: S,
+    ) -> impl Future<Output = Result<(), GcrRequestError>>
+    where
+        S: FnOnce(core::time::Duration) -> F,
+        F: Future<Output = ()>,
+    {
+        let wait = self.reserve_within(n, self.clock.now(), None);
+        async move {
+            let wait = wait?;
+            if !wait.is_zero() {
+                sleep(wait).await;
+            }
+
+            Ok(())
+        }
+    }
+}